@@ -39,6 +39,8 @@ macro_rules! common_apic_methods {
 }
 pub const APIC_TIMER_PERIODIC: u32 = 0x20000;
 pub const APIC_MASKED: u32 = 0x10000;
+/// Delivery mode of a local vector table entry that raises an NMI.
+pub const APIC_DELIVERY_NMI: u32 = 0b100 << 8;
 
 ////////////////////////////////////
 // REGISTERS
@@ -46,11 +48,28 @@ pub const APIC_MASKED: u32 = 0x10000;
 /// The register for local APIC ID.
 pub const LAPIC_ID_REG: usize = 0x020;
 
+/// The end of interrupt register.
+pub const LAPIC_EOI_REG: usize = 0x0B0;
+
+/// The error status register.
+pub const LAPIC_ESR_REG: usize = 0x280;
+
+/// The low half of the interrupt command register. Writing to it sends the IPI.
+///
+/// See ICR format at https://wiki.osdev.org/APIC#Interrupt_Command_Register
+pub const LAPIC_ICR_LOW_REG: usize = 0x300;
+
+/// The high half of the interrupt command register, which holds the destination.
+pub const LAPIC_ICR_HIGH_REG: usize = 0x310;
+
 /// The local vector table for LAPIC timer.
 ///
 /// See LVT format at https://wiki.osdev.org/APIC#Local_Vector_Table_Registers
 pub const LAPIC_LVT_TIMER_REG: usize = 0x320;
 
+/// The local vector table for performance counter overflows.
+pub const LAPIC_LVT_PERF_REG: usize = 0x340;
+
 pub const LAPIC_LVT_LINT0_REG: usize = 0x350;
 
 pub const LAPIC_LVT_LINT1_REG: usize = 0x360;
@@ -74,7 +93,7 @@ pub struct Lapic {
 impl Lapic {
     #[inline]
     pub unsafe fn end_of_interrupt(&mut self) {
-        self.write_register(0xB0, 0);
+        self.write_register(LAPIC_EOI_REG, 0);
     }
 
     pub unsafe fn register_at(&mut self, offset: usize) -> *mut u32 {
@@ -82,7 +101,7 @@ impl Lapic {
     }
 
     pub unsafe fn icr_wait_for_delivery(&mut self) {
-        while self.read_register(0x300) & (1 << 12) != 0 {
+        while self.read_register(LAPIC_ICR_LOW_REG) & (1 << 12) != 0 {
             spin_loop()
         }
     }
@@ -154,6 +173,7 @@ pub fn init_lapic(platform_info: &PlatformInfo, mapper: &Mapper) {
     unsafe {
         for reg in [
             LAPIC_LVT_TIMER_REG,
+            LAPIC_LVT_PERF_REG,
             LAPIC_LVT_LINT0_REG,
             LAPIC_LVT_LINT1_REG,
         ] {
@@ -235,3 +255,52 @@ pub fn init_ioapic(platform_info: &PlatformInfo, mapper: &Mapper) -> (IoApic, u8
 
     found_reg.expect("could not find redirected PIT IRQ")
 }
+
+/// Routes the legacy ISA interrupt `irq` to `vector` on the bootstrap processor.
+///
+/// Must be called after [`init_ioapic`], which masks every other entry.
+pub fn route_isa_irq(
+    platform_info: &PlatformInfo,
+    mapper: &Mapper,
+    irq: u8,
+    vector: InterruptIndex,
+) {
+    let apic = match &platform_info.interrupt_model {
+        InterruptModel::Apic(apic) => apic,
+        _ => panic!("unknown interrupt model"),
+    };
+
+    // ISA interrupts are identity mapped unless overridden.
+    let gsi = apic
+        .interrupt_source_overrides
+        .iter()
+        .find(|ov| ov.isa_source == irq)
+        .map_or(irq as u32, |ov| ov.global_system_interrupt);
+
+    let bsp_apic_id = platform_info
+        .processor_info
+        .as_ref()
+        .expect("apic proc info")
+        .boot_processor
+        .local_apic_id;
+
+    for io_apic in &apic.io_apics {
+        let mut ioapic = IoApic {
+            start_ptr: mapper.phys_to_virt_ptr(io_apic.address as usize),
+        };
+        let base = io_apic.global_system_interrupt_base;
+        let max_redir_count = (unsafe { ioapic.read_register(IOAPICVER) } >> 16) as u8 as u32 + 1;
+        if !(base..base + max_redir_count).contains(&gsi) {
+            continue;
+        }
+
+        let reg = 0x10 + (gsi - base) as u8 * 2;
+        unsafe {
+            ioapic.write_register(reg, vector.as_u8() as u32);
+            ioapic.write_register(reg + 1, bsp_apic_id << 24);
+        }
+        return;
+    }
+
+    panic!("no I/O APIC handles IRQ {irq}");
+}
@@ -0,0 +1,187 @@
+//! Saving and restoring the registers of kernel threads.
+//!
+//! A thread that is switched out keeps its callee-saved registers and `rflags`
+//! on its own stack, so all that needs to be remembered is its stack pointer.
+//! The kernel is built without SSE, so there is no floating point state to save.
+//!
+//! The same technique provides catch points, which a panic can jump back to
+//! without unwinding the frames in between.
+
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+/// The saved stack pointer of a thread that is not running.
+#[repr(transparent)]
+pub struct Context {
+    rsp: u64,
+}
+
+global_asm!(
+    "
+    .global nacl_switch_context
+nacl_switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, [rsi]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+"
+);
+
+extern "C" {
+    fn nacl_switch_context(old: *mut Context, new: *const Context);
+}
+
+/// `rflags` a new thread starts with: only the reserved bit, interrupts disabled.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+impl Context {
+    /// A context that is filled in when its thread is switched out for the
+    /// first time, used for code that is already running.
+    pub const fn empty() -> Context {
+        Context { rsp: 0 }
+    }
+
+    /// Prepares the stack ending at `stack_top` so that switching to the
+    /// returned context calls `entry` with interrupts disabled.
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be 16 byte aligned and point right after a writable
+    /// stack that stays alive while the thread runs.
+    pub unsafe fn new(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> Context {
+        assert!(stack_top.is_aligned(16u64));
+
+        // popped by `nacl_switch_context` from the bottom up. The zero on top is
+        // where `entry` expects a return address, which keeps its stack aligned.
+        let frame = [
+            0, // r15
+            0, // r14
+            0, // r13
+            0, // r12
+            0, // rbx
+            0, // rbp
+            INITIAL_RFLAGS,
+            entry as usize as u64,
+            0,
+        ];
+        let rsp = stack_top.as_mut_ptr::<u64>().sub(frame.len());
+        rsp.copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+
+        Context { rsp: rsp as u64 }
+    }
+}
+
+/// Saves the running thread into `old` and continues the thread saved in `new`.
+///
+/// Returns when another thread switches back to `old`.
+///
+/// # Safety
+///
+/// Interrupts must be disabled, and `new` must be a context that was created
+/// with [`Context::new`] or saved by a previous switch and not resumed since.
+#[inline]
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    nacl_switch_context(old, new);
+}
+
+/// The state [`call_with_catch`] resumes from when [`throw`] is called.
+#[repr(transparent)]
+pub struct CatchPoint {
+    rsp: u64,
+}
+
+global_asm!(
+    "
+    .global nacl_call_with_catch
+nacl_call_with_catch:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rdi, rdx
+    call rsi
+    xor eax, eax
+    jmp 2f
+
+    .global nacl_throw
+nacl_throw:
+    mov rsp, [rdi]
+    mov eax, 1
+2:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+"
+);
+
+extern "C" {
+    fn nacl_call_with_catch(
+        point: *mut CatchPoint,
+        f: extern "C" fn(*mut u8),
+        arg: *mut u8,
+    ) -> bool;
+    fn nacl_throw(point: *const CatchPoint) -> !;
+}
+
+impl CatchPoint {
+    pub const fn new() -> CatchPoint {
+        CatchPoint { rsp: 0 }
+    }
+}
+
+impl Default for CatchPoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f`. Returns `false` if it was abandoned by calling [`throw`] with
+/// `point`, in which case the frames of `f` are skipped without running any
+/// destructors.
+///
+/// # Safety
+///
+/// `point` must stay valid until this returns.
+pub unsafe fn call_with_catch<F: FnOnce()>(point: *mut CatchPoint, f: F) -> bool {
+    extern "C" fn call<F: FnOnce()>(f: *mut u8) {
+        let f = unsafe { (*f.cast::<Option<F>>()).take().unwrap() };
+        f();
+    }
+
+    let mut f = Some(f);
+    !nacl_call_with_catch(point, call::<F>, (&mut f as *mut Option<F>).cast())
+}
+
+/// Continues after the [`call_with_catch`] that set `point`, restoring the
+/// interrupt flag it was called with.
+///
+/// # Safety
+///
+/// Must be called on the same stack, below the frame of the `call_with_catch`
+/// using `point`, while that call is still running.
+pub unsafe fn throw(point: *const CatchPoint) -> ! {
+    nacl_throw(point)
+}
@@ -1,55 +1,126 @@
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use core::cell::Cell;
+
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::cores::percpu;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const IST_STACK_SIZE: usize = 4096 * 5;
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+/// The descriptor tables of a processor.
+struct Tables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
 
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+percpu! {
+    static TABLES: Cell<Option<&'static Tables>> = Cell::new(None);
+}
+
+/// Allocates a stack for the interrupt stack table, returning its top.
+fn alloc_ist_stack() -> VirtAddr {
+    let layout = Layout::from_size_align(IST_STACK_SIZE, 16).unwrap();
+    let stack = unsafe { alloc(layout) };
+    assert!(!stack.is_null(), "allocating IST stack");
+
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE
+}
+
+/// A TSS with the interrupt stacks `ist_stack` returns for each index.
+fn new_tss(mut ist_stack: impl FnMut(u16) -> VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ] {
+        tss.interrupt_stack_table[index as usize] = ist_stack(index);
+    }
+    tss
+}
+
+fn new_tables(tss: &'static TaskStateSegment) -> Tables {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    // `sysret` expects the user data segment right before the user code segment.
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    Tables {
+        gdt,
+        selectors: Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    }
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+    static ref BOOT_TSS: TaskStateSegment = {
+        static mut STACKS: [[u8; IST_STACK_SIZE]; 3] = [[0; IST_STACK_SIZE]; 3];
+        new_tss(|index| {
+            let stack = unsafe { &raw const STACKS[index as usize] };
+            VirtAddr::from_ptr(stack) + IST_STACK_SIZE
+        })
     };
+    /// The tables of the boot processor until per-CPU data is set up, so that
+    /// the exceptions with their own stacks can be handled from the start.
+    static ref BOOT_TABLES: Tables = new_tables(&BOOT_TSS);
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Returns the segment selectors of this processor.
+pub fn selectors() -> &'static Selectors {
+    &TABLES.get().get().expect("GDT not initialized").selectors
 }
 
+/// Loads the GDT and TSS of the boot processor, which are used until
+/// [`init`] runs. Needs neither the heap nor per-CPU data.
+pub fn init_boot() {
+    load(&BOOT_TABLES);
+}
+
+/// Creates and loads the GDT and TSS of this processor.
+///
+/// Each processor has its own so they do not share the interrupt stacks.
 pub fn init() {
+    let tss = Box::leak(Box::new(new_tss(|_| alloc_ist_stack())));
+    let tables: &'static Tables = Box::leak(Box::new(new_tables(tss)));
+    TABLES.get().set(Some(tables));
+    load(tables);
+}
+
+fn load(tables: &'static Tables) {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
     use x86_64::registers::segmentation::{DS, SS};
-    GDT.0.load();
+
+    tables.gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(tables.selectors.kernel_code);
+        SS::set_reg(tables.selectors.kernel_data);
         // https://github.com/rust-osdev/bootloader/issues/190
-        SS::set_reg(SegmentSelector(0));
         DS::set_reg(SegmentSelector(0));
-        load_tss(GDT.1.tss_selector);
+        load_tss(tables.selectors.tss);
     }
 }
@@ -1,26 +1,45 @@
+use core::cell::Cell;
+use core::fmt;
 use core::num::Wrapping;
 use core::ops::{Index, IndexMut};
 
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{
-    Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    Entry, ExceptionVector, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame,
+    PageFaultErrorCode,
 };
 
 use super::apic::lapic;
-use crate::cores::cpu;
-use crate::{hlt_loop, sprintln};
+use crate::cores::{self, cpu, percpu};
+use crate::{emergency_sprintln, hlt_loop};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
         let options = idt.double_fault.set_handler_fn(double_fault_handler);
         unsafe {
             options.set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        let options = idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        unsafe {
+            options.set_stack_index(super::gdt::NMI_IST_INDEX);
+        }
+        let options = idt.machine_check.set_handler_fn(machine_check_handler);
+        unsafe {
+            options.set_stack_index(super::gdt::MACHINE_CHECK_IST_INDEX);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::ScratchTimer].set_handler_fn(scratch_timer_interrupt_handler);
+        idt[InterruptIndex::Serial].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Reschedule].set_handler_fn(reschedule_interrupt_handler);
+        idt[InterruptIndex::CallFunction].set_handler_fn(call_function_interrupt_handler);
+        idt[InterruptIndex::TlbShootdown].set_handler_fn(tlb_shootdown_interrupt_handler);
         idt
     };
 }
@@ -29,6 +48,65 @@ pub fn init_idt() {
     IDT.load();
 }
 
+percpu! {
+    /// number of exceptions caused by the code running on this processor.
+    static FAULTS: Cell<u64> = Cell::new(0);
+    /// the last of them.
+    static LAST_FAULT: Cell<Option<ExceptionVector>> = Cell::new(None);
+}
+
+/// Returns the number of exceptions caused by the code that ran on this
+/// processor, like page faults. Used by tests that expect one.
+pub fn faults() -> u64 {
+    FAULTS.get().get()
+}
+
+/// Returns the last exception caused by the code that ran on this processor.
+pub fn last_fault() -> Option<ExceptionVector> {
+    LAST_FAULT.get().get()
+}
+
+/// Handles an exception caused by the running code as a panic, so a task that
+/// faults is torn down like a task that panics.
+fn fault(vector: ExceptionVector, args: fmt::Arguments<'_>) -> ! {
+    // before per-CPU data is set up, there is nothing to count with and no
+    // catch point to unwind to.
+    if cores::try_id().is_none() {
+        emergency_sprintln!("EXCEPTION: {}", args);
+        hlt_loop();
+    }
+    FAULTS.get().update(|n| n + 1);
+    LAST_FAULT.get().set(Some(vector));
+    panic!("EXCEPTION: {}", args);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fault(
+        ExceptionVector::Division,
+        format_args!("DIVIDE ERROR\n{:#?}", stack_frame),
+    );
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fault(
+        ExceptionVector::InvalidOpcode,
+        format_args!("INVALID OPCODE\n{:#?}", stack_frame),
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fault(
+        ExceptionVector::GeneralProtection,
+        format_args!(
+            "GENERAL PROTECTION FAULT\nError Code: {:#x}\n{:#?}",
+            error_code, stack_frame
+        ),
+    );
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     crate::sprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -40,25 +118,44 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _nmi = crate::cores::enter_nmi();
+    super::watchdog::handle_nmi(&stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    sprintln!("EXCEPTION: PAGE FAULT");
-    sprintln!("Accessed Address: {:?}", Cr2::read());
-    sprintln!("Error Code: {:?}", error_code);
-    sprintln!("{:#?}", stack_frame);
-    hlt_loop();
+    fault(
+        ExceptionVector::Page,
+        format_args!(
+            "PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
+            Cr2::read(),
+            error_code,
+            stack_frame
+        ),
+    );
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    cpu().timer.update(|n| n + Wrapping(1));
+    {
+        let _irq = crate::cores::enter_irq();
+        cpu().timer.update(|n| n + Wrapping(1));
 
-    unsafe {
-        lapic().end_of_interrupt();
+        unsafe {
+            lapic().end_of_interrupt();
+        }
     }
+
+    // may switch to another thread, this returns when switched back.
+    crate::task::thread::tick();
 }
 
 /// The scratch timer is used when calibrating two clocks that both use IRQs
@@ -73,6 +170,41 @@ extern "x86-interrupt" fn scratch_timer_interrupt_handler(_stack_frame: Interrup
     }
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = crate::cores::enter_irq();
+    crate::serial::receive_pending();
+
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
+/// Only used to wake the processor up, the woken executor looks for work by itself.
+extern "x86-interrupt" fn reschedule_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = crate::cores::enter_irq();
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn call_function_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = crate::cores::enter_irq();
+    super::ipi::handle_pending();
+
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = crate::cores::enter_irq();
+    super::ipi::handle_pending();
+
+    unsafe {
+        lapic().end_of_interrupt();
+    }
+}
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
@@ -81,6 +213,13 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = 32,
     ScratchTimer = 33,
+    /// IRQ 4, the first serial port.
+    Serial = 36,
+
+    // vectors reserved for inter-processor interrupts.
+    Reschedule = 0xFC,
+    CallFunction = 0xFD,
+    TlbShootdown = 0xFE,
 }
 
 impl InterruptIndex {
@@ -0,0 +1,321 @@
+//! Inter-processor interrupts.
+//!
+//! Besides raw IPIs, this provides two synchronous cross-CPU requests: running a
+//! function on other processors and shooting down their TLB entries. The sender
+//! keeps the request on its stack and spins until every target has handled it.
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+use super::apic::{lapic, LAPIC_ICR_HIGH_REG, LAPIC_ICR_LOW_REG};
+use super::interrupts::InterruptIndex;
+use crate::cores::{self, percpu, PerCpu};
+
+/// The processors an IPI is sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// A single processor, identified by its number (see [`cores::id`]).
+    Cpu(u32),
+    /// The sending processor.
+    SelfOnly,
+    /// All processors, including the sender.
+    All,
+    /// All processors except the sender.
+    AllButSelf,
+}
+
+impl Destination {
+    /// The destination shorthand bits of the ICR.
+    fn shorthand(self) -> u32 {
+        match self {
+            Destination::Cpu(_) => 0b00,
+            Destination::SelfOnly => 0b01,
+            Destination::All => 0b10,
+            Destination::AllButSelf => 0b11,
+        }
+    }
+
+    /// Whether the processor `cpu` is targeted when `me` sends to this destination.
+    fn contains(self, cpu: u32, me: u32) -> bool {
+        match self {
+            Destination::Cpu(id) => id == cpu,
+            Destination::SelfOnly => cpu == me,
+            Destination::All => true,
+            Destination::AllButSelf => cpu != me,
+        }
+    }
+}
+
+/// How the IPI is delivered to the target processors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Deliver the interrupt at the given vector.
+    Fixed(u8),
+    /// Deliver a non-maskable interrupt. The vector is ignored.
+    Nmi,
+    /// Put the target processors in the INIT state.
+    Init,
+    /// Start up processors in the wait-for-SIPI state at the page `vector * 0x1000`.
+    Startup(u8),
+}
+
+impl DeliveryMode {
+    /// The vector and delivery mode bits of the ICR.
+    fn bits(self) -> u32 {
+        let (mode, vector) = match self {
+            DeliveryMode::Fixed(vector) => (0b000, vector),
+            DeliveryMode::Nmi => (0b100, 0),
+            DeliveryMode::Init => (0b101, 0),
+            DeliveryMode::Startup(vector) => (0b110, vector),
+        };
+        mode << 8 | vector as u32
+    }
+}
+
+/// Sends an IPI and waits for the local APIC to accept it.
+pub fn send_raw(dest: Destination, mode: DeliveryMode) {
+    let apic_id = match dest {
+        Destination::Cpu(id) => cores::apic_id_of(id),
+        _ => 0,
+    };
+
+    // level assert, edge triggered, physical destination mode.
+    let low = mode.bits() | 1 << 14 | dest.shorthand() << 18;
+
+    // an interrupt handler sending an IPI between the two writes would
+    // clobber the destination, so do this with interrupts disabled.
+    without_interrupts(|| {
+        let mut lapic = lapic();
+        unsafe {
+            lapic.icr_wait_for_delivery();
+            lapic.write_register(LAPIC_ICR_HIGH_REG, apic_id << 24);
+            lapic.write_register(LAPIC_ICR_LOW_REG, low);
+            lapic.icr_wait_for_delivery();
+        }
+    })
+}
+
+/// Sends the interrupt `vector` to `dest`.
+#[inline]
+pub fn send(dest: Destination, vector: InterruptIndex) {
+    send_raw(dest, DeliveryMode::Fixed(vector.as_u8()));
+}
+
+/// Sends a non-maskable interrupt to `dest`.
+#[inline]
+pub fn send_nmi(dest: Destination) {
+    send_raw(dest, DeliveryMode::Nmi);
+}
+
+/// Asks the processor `cpu` to look for new work, waking it up if it is halted.
+#[inline]
+pub fn reschedule(cpu: u32) {
+    send(Destination::Cpu(cpu), InterruptIndex::Reschedule);
+}
+
+/// The TLB entries to invalidate in a shootdown.
+#[derive(Clone, Copy, Debug)]
+pub enum Flush {
+    /// All non-global entries.
+    All,
+    /// The 4KiB pages starting at `start`.
+    Pages { start: VirtAddr, count: usize },
+}
+
+impl Flush {
+    fn run(self) {
+        match self {
+            Flush::All => tlb::flush_all(),
+            Flush::Pages { start, count } => {
+                for i in 0..count as u64 {
+                    tlb::flush(start + i * 4096);
+                }
+            }
+        }
+    }
+}
+
+enum RequestKind<'a> {
+    Call(&'a (dyn Fn() + Sync)),
+    Flush(Flush),
+}
+
+impl RequestKind<'_> {
+    fn run(&self) {
+        match self {
+            RequestKind::Call(f) => f(),
+            RequestKind::Flush(flush) => flush.run(),
+        }
+    }
+}
+
+struct Request<'a> {
+    kind: RequestKind<'a>,
+    /// number of targets that have not handled this request yet, plus one for the sender.
+    pending: AtomicUsize,
+}
+
+/// A slot for one in-flight request of a kind, one per reserved vector.
+struct Mailbox {
+    vector: InterruptIndex,
+    lock: spin::Mutex<()>,
+    request: AtomicPtr<Request<'static>>,
+    /// whether a processor still has to handle the current request.
+    pending: &'static PerCpu<AtomicBool>,
+}
+
+percpu! {
+    static CALL_FUNCTION_PENDING: AtomicBool = AtomicBool::new(false);
+    static TLB_SHOOTDOWN_PENDING: AtomicBool = AtomicBool::new(false);
+}
+
+static CALL_FUNCTION: Mailbox = Mailbox::new(InterruptIndex::CallFunction, &CALL_FUNCTION_PENDING);
+static TLB_SHOOTDOWN: Mailbox = Mailbox::new(InterruptIndex::TlbShootdown, &TLB_SHOOTDOWN_PENDING);
+
+impl Mailbox {
+    const fn new(vector: InterruptIndex, pending: &'static PerCpu<AtomicBool>) -> Mailbox {
+        Mailbox {
+            vector,
+            lock: spin::Mutex::new(()),
+            request: AtomicPtr::new(ptr::null_mut()),
+            pending,
+        }
+    }
+
+    /// Runs `kind` on every online processor in `dest` and waits for all of them.
+    fn post(&self, dest: Destination, kind: RequestKind<'_>) {
+        let me = cores::id();
+
+        // other processors may be waiting on us while we wait for the lock.
+        let guard = loop {
+            if let Some(guard) = self.lock.try_lock() {
+                break guard;
+            }
+            handle_pending();
+            spin_loop();
+        };
+
+        // the sender holds one count itself, so targets handling the request
+        // early cannot drop it to zero before every target is flagged.
+        let request = Request {
+            kind,
+            pending: AtomicUsize::new(1),
+        };
+        self.request.store(
+            &request as *const Request<'_> as *mut Request<'static>,
+            Ordering::Release,
+        );
+        for cpu in cores::online().filter(|&cpu| cpu != me && dest.contains(cpu, me)) {
+            request.pending.fetch_add(1, Ordering::Relaxed);
+            self.pending.get_for(cpu).store(true, Ordering::Release);
+        }
+
+        match dest {
+            Destination::All => send(Destination::AllButSelf, self.vector),
+            Destination::Cpu(cpu) if cpu == me => {}
+            Destination::SelfOnly => {}
+            dest => send(dest, self.vector),
+        }
+
+        if dest.contains(me, me) {
+            request.kind.run();
+        }
+        request.pending.fetch_sub(1, Ordering::Release);
+
+        while request.pending.load(Ordering::Acquire) != 0 {
+            handle_pending();
+            spin_loop();
+        }
+
+        self.request.store(ptr::null_mut(), Ordering::Relaxed);
+        drop(guard);
+    }
+
+    /// Handles the current request if this processor is one of its targets.
+    fn handle(&self) {
+        if !self.pending.get().swap(false, Ordering::Acquire) {
+            return;
+        }
+
+        // SAFETY: the sender keeps the request alive until `pending` drops to zero.
+        let request = unsafe { &*self.request.load(Ordering::Acquire) };
+        request.kind.run();
+        request.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Handles requests addressed to this processor that have not been handled yet.
+///
+/// Called from the IPI handlers, and by senders spinning with interrupts
+/// possibly disabled so two processors posting to each other cannot deadlock.
+pub fn handle_pending() {
+    CALL_FUNCTION.handle();
+    TLB_SHOOTDOWN.handle();
+}
+
+/// Runs `f` on all online processors in `dest` and waits for them to finish.
+///
+/// `f` runs in interrupt context on the other processors, so it must not block.
+pub fn call_function(dest: Destination, f: &(dyn Fn() + Sync)) {
+    CALL_FUNCTION.post(dest, RequestKind::Call(f));
+}
+
+/// Invalidates TLB entries on all online processors, including this one.
+///
+/// Must be called after changing page table entries that other processors may have cached.
+pub fn tlb_shootdown(flush: Flush) {
+    TLB_SHOOTDOWN.post(Destination::All, RequestKind::Flush(flush));
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    use super::{call_function, send, tlb_shootdown, Destination, Flush};
+    use super::super::interrupts::InterruptIndex;
+    use crate::cores;
+    use crate::testing::kernel_test;
+
+    #[kernel_test]
+    fn call_function_all() {
+        let ran = AtomicUsize::new(0);
+        call_function(Destination::All, &|| {
+            ran.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(ran.load(Ordering::Relaxed), cores::online().count());
+    }
+
+    #[kernel_test]
+    fn call_function_all_but_self() {
+        let me = cores::id();
+        let ran = AtomicUsize::new(0);
+        call_function(Destination::AllButSelf, &|| {
+            assert_ne!(cores::id(), me);
+            ran.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(ran.load(Ordering::Relaxed), cores::online().count() - 1);
+    }
+
+    #[kernel_test]
+    fn call_function_each_cpu() {
+        for cpu in cores::online() {
+            let ran_on = AtomicU32::new(u32::MAX);
+            call_function(Destination::Cpu(cpu), &|| {
+                ran_on.store(cores::id(), Ordering::Relaxed);
+            });
+            assert_eq!(ran_on.load(Ordering::Relaxed), cpu);
+        }
+    }
+
+    #[kernel_test]
+    fn self_ipi() {
+        // returns once the local APIC accepted it, the handler runs whenever.
+        send(Destination::SelfOnly, InterruptIndex::Reschedule);
+        tlb_shootdown(Flush::All);
+    }
+}
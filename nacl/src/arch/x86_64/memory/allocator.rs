@@ -7,7 +7,9 @@ use x86_64::VirtAddr;
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Every processor takes 60 KiB of interrupt stacks and its per-CPU area from
+/// the heap, and every thread a 32 KiB stack, which 100 KiB did not fit.
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
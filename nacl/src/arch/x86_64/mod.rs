@@ -3,14 +3,20 @@ use stivale_boot::v2::StivaleStruct;
 mod acpi;
 pub mod apic;
 mod boot;
-mod gdt;
+pub mod context;
+pub mod gdt;
 mod interrupts;
+pub mod ipi;
 mod memory;
+mod power;
 // mod smp;
 mod time;
+pub mod watchdog;
 
 pub use memory::init as memory_init;
-pub use time::delay;
+pub use interrupts::{faults, last_fault};
+pub use power::{exit_qemu, power_off, reboot, QemuExitCode};
+pub use time::{delay, tsc, tsc_to_duration};
 
 use crate::sprintln;
 
@@ -18,17 +24,27 @@ use self::memory::mapper::Mapper;
 
 
 pub fn init(physical_memory_offset: usize, rsdp_addr: usize) {
-    gdt::init();
+    // the IDT names interrupt stacks, which only a loaded TSS provides.
+    gdt::init_boot();
     interrupts::init_idt();
 
     let mapper = Mapper::new(physical_memory_offset);
     let tables = acpi::get_acpi_tables(rsdp_addr, mapper);
     let platform_info = acpi::get_platform_info(&tables);
+    crate::cores::init(
+        platform_info
+            .processor_info
+            .as_ref()
+            .expect("processor info"),
+    );
+    gdt::init();
     apic::init_and_disable_old_pic();
     apic::init_lapic(&platform_info, &mapper);
 
     let (ioapic, pitreg) = apic::init_ioapic(&platform_info, &mapper);
     time::init(ioapic, pitreg);
+    apic::route_isa_irq(&platform_info, &mapper, 4, interrupts::InterruptIndex::Serial);
+    watchdog::init();
     // smp::init(&platform_info, boot_info);
 
     x86_64::instructions::interrupts::enable();
@@ -0,0 +1,79 @@
+//! Powering off and resetting the machine, and exiting QEMU.
+
+use core::arch::asm;
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::hlt_loop;
+
+/// Powers the machine off.
+///
+/// Entering the ACPI S5 state properly needs an AML interpreter, so this only
+/// knows the ports emulators use for it. Halts if none of them worked.
+pub fn power_off() -> ! {
+    interrupts::disable();
+    unsafe {
+        // QEMU
+        Port::<u16>::new(0x604).write(0x2000);
+        // Bochs and older versions of QEMU
+        Port::<u16>::new(0xB004).write(0x2000);
+        // VirtualBox
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+    hlt_loop()
+}
+
+/// Number of times to poll the keyboard controller before resetting anyway.
+const RESET_TRIES: u32 = 100_000;
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe {
+        // pulse the reset line through the keyboard controller, once its input
+        // buffer is empty. Without a controller the port reads 0xFF, so give up
+        // waiting after a while.
+        let mut status = PortReadOnly::<u8>::new(0x64);
+        for _ in 0..RESET_TRIES {
+            if status.read() & 1 << 1 == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Port::<u8>::new(0x64).write(0xFE);
+
+        // otherwise triple fault: no exception can be handled without an IDT.
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3", options(nomem, nostack));
+    }
+    hlt_loop()
+}
+
+/// I/O port of the QEMU device started with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+pub const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Values written to the `isa-debug-exit` device. QEMU exits with the status
+/// `(code << 1) | 1`, so 33 for success and 35 for failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU with `code`. Powers off if QEMU has no `isa-debug-exit` device.
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    interrupts::disable();
+    unsafe {
+        Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32);
+    }
+    power_off()
+}
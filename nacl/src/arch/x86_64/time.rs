@@ -1,6 +1,7 @@
+use core::arch::x86_64::_rdtsc;
 use core::num::Wrapping;
-use core::sync::atomic::AtomicU32;
 use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU32, AtomicU64};
 use core::time::Duration;
 
 use x86_64::instructions::port::Port;
@@ -20,6 +21,9 @@ use crate::sprintln;
 /// Note that this is NOT the number of IRQs per 10ms.
 static APIC_TICKS_IN_10MS: AtomicU32 = AtomicU32::new(0);
 
+/// number of time stamp counter cycles in 10ms, measured alongside the APIC timer.
+static TSC_IN_10MS: AtomicU64 = AtomicU64::new(0);
+
 fn get_irq_cnt() -> Wrapping<usize> {
     cpu().timer.get()
 }
@@ -74,9 +78,11 @@ fn calibrate_apic_timer(mut ioapic: IoApic, pitreg: u8) {
     unsafe {
         lapic.write_register(LAPIC_TIMER_INITCNT_REG, u32::MAX);
     }
+    let tsc_start = tsc();
 
     // wait for another IRQ from the PIT.
     while get_irq_cnt() - curr_pit_cnt < Wrapping(1) {}
+    let tsc_in_10ms = tsc() - tsc_start;
 
     // Stop the APIC timer
     unsafe {
@@ -91,6 +97,7 @@ fn calibrate_apic_timer(mut ioapic: IoApic, pitreg: u8) {
     sprintln!("apic ticks in 10ms = {apic_ticks_in_10ms}");
 
     APIC_TICKS_IN_10MS.store(apic_ticks_in_10ms, Relaxed);
+    TSC_IN_10MS.store(tsc_in_10ms, Relaxed);
 
     // mask the PIT I/O APIC entry.
     unsafe { ioapic.write_register(pitreg, APIC_MASKED) }
@@ -121,6 +128,22 @@ pub fn init(ioapic: IoApic, pitreg: u8) {
     calibrate_apic_timer(ioapic, pitreg);
 }
 
+/// Reads the time stamp counter of this processor.
+#[inline]
+pub fn tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Converts a number of time stamp counter cycles to a duration.
+///
+/// Returns zero before the timer is calibrated.
+pub fn tsc_to_duration(cycles: u64) -> Duration {
+    match TSC_IN_10MS.load(Relaxed) {
+        0 => Duration::ZERO,
+        per_10ms => Duration::from_nanos((cycles as u128 * 10_000_000 / per_10ms as u128) as u64),
+    }
+}
+
 /// precision microsecond delay, `micros` should not be larger than 1000.
 pub fn udelay(micros: usize) {
     // instead of using the IRQ counter, we need to read the current count
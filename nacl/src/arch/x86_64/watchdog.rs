@@ -0,0 +1,198 @@
+//! Hard-lockup detection.
+//!
+//! Every processor programs its first performance counter to count unhalted
+//! core cycles and raise an NMI when it overflows. The NMI arrives even when
+//! interrupts are disabled, so the handler can check whether the timer interrupt
+//! still makes progress on this processor. A processor that keeps burning cycles
+//! without taking timer interrupts is reported as locked up.
+
+use core::cell::Cell;
+use core::num::Wrapping;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::apic::{lapic, APIC_DELIVERY_NMI, LAPIC_LVT_PERF_REG};
+use super::ipi::{self, Destination};
+use crate::cores::{self, cpu, percpu};
+use crate::{emergency_sprintln, sprintln};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// "UnHalted Core Cycles" architectural event, counted in ring 0 and 3, with
+/// the interrupt on overflow and enable bits set.
+const PERFEVTSEL_UNHALTED_CYCLES: u64 = 0x3C | 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// Number of cycles between two watchdog NMIs. `wrmsr` to a counter only takes
+/// 32 bits sign extended, so this is the largest period that can be programmed.
+const PERIOD: u64 = 1 << 31;
+
+/// Number of watchdog NMIs without a timer interrupt before reporting a lockup.
+const STALL_LIMIT: u32 = 5;
+
+/// Anything above this on the stack may be a return address into the kernel.
+const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// Number of stack slots scanned for return addresses when dumping state.
+const STACK_SCAN: usize = 32;
+
+/// Architectural performance monitoring version, zero if the watchdog is unavailable.
+static VERSION: AtomicU8 = AtomicU8::new(0);
+/// Mask of the valid bits of the performance counters.
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    static ENABLED: Cell<bool> = Cell::new(false);
+    static LAST_TICKS: Cell<Wrapping<usize>> = Cell::new(Wrapping(0));
+    static STALLS: Cell<u32> = Cell::new(0);
+    static BACKTRACE_REQUESTED: AtomicBool = AtomicBool::new(false);
+}
+
+fn arm() {
+    let reload = PERIOD.wrapping_neg() & COUNTER_MASK.load(Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_PMC0).write(reload);
+        // the LAPIC masks the entry every time it delivers the NMI.
+        lapic().write_register(LAPIC_LVT_PERF_REG, APIC_DELIVERY_NMI);
+    }
+}
+
+/// Starts the watchdog on this processor, if the processor supports it.
+///
+/// The timer interrupt must already be configured.
+pub fn init() {
+    if VERSION.load(Ordering::Relaxed) == 0 {
+        let info = CpuId::new().get_performance_monitoring_info();
+        let info = match info {
+            Some(info)
+                if info.version_id() > 0
+                    && info.number_of_counters() > 0
+                    && !info.is_core_cyc_ev_unavailable() =>
+            {
+                info
+            }
+            _ => {
+                sprintln!("watchdog: no usable performance counters");
+                return;
+            }
+        };
+        COUNTER_MASK.store(
+            (1u64 << info.counter_bit_width()) - 1,
+            Ordering::Relaxed,
+        );
+        VERSION.store(info.version_id(), Ordering::Relaxed);
+    }
+
+    LAST_TICKS.get().set(cpu().timer.get());
+    STALLS.get().set(0);
+    arm();
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(PERFEVTSEL_UNHALTED_CYCLES);
+        if VERSION.load(Ordering::Relaxed) >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_CTRL).write(1);
+        }
+    }
+    ENABLED.get().set(true);
+}
+
+/// Returns whether the first performance counter overflowed, acknowledging it.
+fn counter_overflowed() -> bool {
+    if !ENABLED.get().get() {
+        return false;
+    }
+
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        let overflowed = unsafe { Msr::new(IA32_PERF_GLOBAL_STATUS).read() } & 1 != 0;
+        if overflowed {
+            unsafe { Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1) };
+        }
+        overflowed
+    } else {
+        // the counter starts with its top bit set, and clears it on overflow.
+        let mask = COUNTER_MASK.load(Ordering::Relaxed);
+        let top = (mask >> 1) + 1;
+        let counter = unsafe { Msr::new(IA32_PMC0).read() };
+        counter & top == 0
+    }
+}
+
+fn check_progress(stack_frame: &InterruptStackFrame) {
+    let ticks = cpu().timer.get();
+    if ticks != LAST_TICKS.get().get() {
+        LAST_TICKS.get().set(ticks);
+        STALLS.get().set(0);
+        return;
+    }
+
+    let stalls = STALLS.get().get() + 1;
+    STALLS.get().set(stalls);
+    // only report once per lockup.
+    if stalls == STALL_LIMIT {
+        emergency_sprintln!(
+            "watchdog: hard lockup on CPU {}, no timer interrupts for {} cycles",
+            cores::id(),
+            PERIOD * STALL_LIMIT as u64
+        );
+        dump_state(stack_frame);
+    }
+}
+
+/// Prints the interrupted state of this processor.
+pub fn dump_state(stack_frame: &InterruptStackFrame) {
+    emergency_sprintln!(
+        "CPU {}: rip {:?} rsp {:?} rflags {:#x}",
+        cores::id(),
+        stack_frame.instruction_pointer,
+        stack_frame.stack_pointer,
+        stack_frame.cpu_flags
+    );
+
+    // there are no frame pointers, so print anything on the stack that looks
+    // like a kernel address. Stay within the page of the stack pointer so this
+    // cannot fault.
+    let rsp = stack_frame.stack_pointer.as_u64();
+    let slots = ((rsp | 0xFFF) + 1 - rsp) as usize / 8;
+    let rsp = rsp as *const u64;
+    for i in 0..slots.min(STACK_SCAN) {
+        let value = unsafe { rsp.add(i).read_volatile() };
+        if value >= KERNEL_BASE {
+            emergency_sprintln!("  [rsp+{:#04x}] {:#x}", i * 8, value);
+        }
+    }
+}
+
+/// Called by the NMI handler.
+pub(super) fn handle_nmi(stack_frame: &InterruptStackFrame) {
+    let mut handled = false;
+
+    if BACKTRACE_REQUESTED.get().swap(false, Ordering::Acquire) {
+        dump_state(stack_frame);
+        handled = true;
+    }
+
+    if counter_overflowed() {
+        check_progress(stack_frame);
+        arm();
+        handled = true;
+    }
+
+    if !handled {
+        emergency_sprintln!("NMI on CPU {} for unknown reason", cores::id());
+        dump_state(stack_frame);
+    }
+}
+
+/// Makes all other online processors print their state.
+pub fn trigger_all_cpu_backtrace() {
+    let me = cores::id();
+    for cpu in cores::online().filter(|&cpu| cpu != me) {
+        BACKTRACE_REQUESTED.get_for(cpu).store(true, Ordering::Release);
+    }
+    ipi::send_nmi(Destination::AllButSelf);
+}
@@ -0,0 +1,140 @@
+//! A line based command console on the first serial port.
+
+use alloc::format;
+use alloc::string::String;
+
+use crate::shutdown::{self, Action};
+use crate::task::info::tasks_on;
+use crate::task::panic::{self, PanicMode};
+use crate::{cores, serial, sprint, sprintln};
+
+const PROMPT: &str = "> ";
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(args: &str),
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "tasks",
+        help: "list the live tasks of every processor",
+        run: tasks,
+    },
+    Command {
+        name: "panic",
+        help: "show or set what panics do: `isolate` tasks or `halt`",
+        run: panic_mode,
+    },
+    Command {
+        name: "poweroff",
+        help: "stop all tasks and power off",
+        run: |_| shutdown::shutdown(Action::PowerOff),
+    },
+    Command {
+        name: "reboot",
+        help: "stop all tasks and reboot",
+        run: |_| shutdown::shutdown(Action::Reboot),
+    },
+];
+
+fn help(_args: &str) {
+    for command in COMMANDS {
+        sprintln!("{:<8} {}", command.name, command.help);
+    }
+}
+
+fn tasks(_args: &str) {
+    for cpu in cores::online() {
+        let tasks = tasks_on(cpu);
+        sprintln!("CPU {}: {} tasks", cpu, tasks.len());
+        if tasks.is_empty() {
+            continue;
+        }
+
+        sprintln!(
+            "  {:>5} {:<16} {:<11} {:>8} {:>8} {:>12} {:>12}  spawned at",
+            "id",
+            "name",
+            "priority",
+            "polls",
+            "wakes",
+            "busy",
+            "max poll"
+        );
+        for task in tasks {
+            sprintln!(
+                "  {:>5} {:<16} {:<11} {:>8} {:>8} {:>12} {:>12}  {}",
+                task.id(),
+                task.name(),
+                format!("{:?}", task.priority()),
+                task.polls(),
+                task.wakes(),
+                format!("{:?}", task.busy()),
+                format!("{:?}", task.max_poll()),
+                task.spawned_at()
+            );
+        }
+    }
+}
+
+fn panic_mode(args: &str) {
+    match args {
+        "" => {}
+        "isolate" => panic::set_mode(PanicMode::Isolate),
+        "halt" => panic::set_mode(PanicMode::Halt),
+        _ => {
+            sprintln!("usage: panic [isolate|halt]");
+            return;
+        }
+    }
+    sprintln!("panic mode: {:?}", panic::mode());
+}
+
+fn execute(line: &str) {
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    if name.is_empty() {
+        return;
+    }
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim()),
+        None => sprintln!("unknown command `{}`, try `help`", name),
+    }
+}
+
+/// Reads commands from the serial port and runs them, until shutdown.
+pub async fn run() {
+    let mut line = String::new();
+    sprint!("{}", PROMPT);
+    loop {
+        let read = shutdown::token().run_until_cancelled(serial::read_byte());
+        let Some(byte) = read.await else {
+            return;
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                sprintln!();
+                execute(line.trim());
+                line.clear();
+                sprint!("{}", PROMPT);
+            }
+            // backspace and delete
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    sprint!("\x08 \x08");
+                }
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => {
+                line.push(byte as char);
+                sprint!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,26 +1,35 @@
+//! Core-local state.
+//!
+//! Every processor discovered in the MADT gets its own per-CPU area, and the
+//! `GS` base of each processor points to the area it owns. An area starts with
+//! a small header followed by a copy of the `.percpu` section, which holds the
+//! initial values of all variables declared with [`percpu!`].
+
+use alloc::alloc::{alloc, Layout};
 use alloc::boxed::Box;
-use core::array;
-use core::cell::{Cell, RefCell};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cell::{Cell, RefCell, UnsafeCell};
 use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
 use core::num::Wrapping;
+use core::ptr::{self, addr_of, NonNull};
 use core::sync::atomic::{AtomicBool, Ordering};
 
+use acpi::platform::{ProcessorInfo, ProcessorState};
 use crossbeam_epoch::LocalHandle;
-use stivale_boot::v2::StivaleSmpInfo;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
 
-use crate::task::crossbeam::{Stealer, Worker};
 use crate::task::executor::Executor;
-use crate::task::TaskId;
-
-pub const MAX_NUM_CPUS: usize = 64;
+use crate::task::priority::{RunQueueStealers, RunQueues};
 
 /// a large structure containing core-local state.
 pub struct Cpu {
     pub timer: Cell<Wrapping<usize>>,
     pub local_handle: LocalHandle,
     pub executor: RefCell<Executor>,
-    pub worker: Worker<TaskId>,
+    pub run_queues: RunQueues,
 }
 
 impl Cpu {
@@ -29,7 +38,7 @@ impl Cpu {
             timer: Cell::new(Wrapping(0)),
             local_handle: crate::task::gc::default_collector().register(),
             executor: RefCell::default(),
-            worker: Worker::new_fifo(),
+            run_queues: RunQueues::new(),
         }
     }
 }
@@ -48,15 +57,6 @@ pub struct CpuKey<'a> {
     _lt_invariant: PhantomData<fn(&'a ()) -> &'a ()>,
 }
 
-/// Returns a unique identifying number of this processor.
-pub fn id() -> u32 {
-    crate::arch::apic::lapic().id()
-}
-
-#[allow(clippy::declare_interior_mutable_const)]
-const CPU_SCOPE_INIT: AtomicBool = AtomicBool::new(false);
-static CPU_ENTERS: [AtomicBool; MAX_NUM_CPUS] = [CPU_SCOPE_INIT; MAX_NUM_CPUS];
-
 impl CpuKey<'static> {
     pub fn scope<F, T>(callback: F) -> T
     where
@@ -73,25 +73,383 @@ impl CpuKey<'static> {
     }
 }
 
-const CPU_INIT: Option<&'static Cpu> = None;
-static mut CPUS: [Option<&'static Cpu>; MAX_NUM_CPUS] = [CPU_INIT; MAX_NUM_CPUS];
+extern "C" {
+    /// start of the per-CPU template, defined in the linker script.
+    static __percpu_start: u8;
+    /// end of the per-CPU template, defined in the linker script.
+    static __percpu_end: u8;
+}
+
+/// The largest alignment a per-CPU variable may have.
+const PERCPU_ALIGN: usize = 64;
+
+/// The start of every per-CPU area. `GS` points here.
+#[repr(C, align(64))]
+struct Header {
+    /// address of this header, so it can be read through `GS`.
+    this: *const Header,
+    /// the number of this processor, counted from zero in MADT order.
+    id: u32,
+    /// the local APIC ID of this processor.
+    apic_id: u32,
+    cpu: *const Cpu,
+    online: AtomicBool,
+}
+
+impl Header {
+    /// Returns the per-CPU variables that follow this header.
+    fn data(&self) -> *const u8 {
+        unsafe { (self as *const Header).add(1).cast() }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Area(NonNull<Header>);
+
+// SAFETY: the header is only mutated through atomics after initialization.
+unsafe impl Send for Area {}
+unsafe impl Sync for Area {}
+
+impl Area {
+    fn header(&self) -> &'static Header {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+static AREAS: spin::Once<Box<[Area]>> = spin::Once::new();
+static STEALERS: spin::Once<Box<[RunQueueStealers]>> = spin::Once::new();
+
+fn areas() -> &'static [Area] {
+    AREAS.get().expect("per-CPU areas are not initialized")
+}
+
+/// Allocates a per-CPU area for every usable processor and installs the one of
+/// the bootstrap processor.
+pub fn init(processor_info: &ProcessorInfo) {
+    let processors = core::iter::once(&processor_info.boot_processor).chain(
+        processor_info
+            .application_processors
+            .iter()
+            .filter(|p| p.state != ProcessorState::Disabled),
+    );
+
+    let template_start = addr_of!(__percpu_start);
+    let template_len = addr_of!(__percpu_end) as usize - template_start as usize;
+    let layout =
+        Layout::from_size_align(size_of::<Header>() + template_len, PERCPU_ALIGN).unwrap();
+
+    let mut areas = Vec::new();
+    let mut stealers = Vec::new();
+    for (id, processor) in processors.enumerate() {
+        let cpu: &'static Cpu = Box::leak(Box::new(Cpu::new()));
+        stealers.push(cpu.run_queues.stealers());
+
+        let header = unsafe { alloc(layout) }.cast::<Header>();
+        let header = NonNull::new(header).expect("allocating per-CPU area");
+        unsafe {
+            header.as_ptr().write(Header {
+                this: header.as_ptr(),
+                id: id as u32,
+                apic_id: processor.local_apic_id,
+                cpu,
+                online: AtomicBool::new(false),
+            });
+            let data = header.as_ref().data() as *mut u8;
+            ptr::copy_nonoverlapping(template_start, data, template_len);
+        }
+        areas.push(Area(header));
+    }
+
+    AREAS.call_once(|| areas.into_boxed_slice());
+    STEALERS.call_once(|| stealers.into_boxed_slice());
+
+    install(processor_info.boot_processor.local_apic_id);
+}
+
+/// Points `GS` of this processor to the per-CPU area of the processor with the
+/// local APIC ID `apic_id`, and marks it online.
+pub fn install(apic_id: u32) {
+    let area = areas()
+        .iter()
+        .find(|area| area.header().apic_id == apic_id)
+        .expect("processor not in MADT");
+
+    GsBase::write(VirtAddr::from_ptr(area.0.as_ptr()));
+    area.header().online.store(true, Ordering::Release);
+}
+
+#[inline]
+fn header() -> &'static Header {
+    let this: *const Header;
+    // SAFETY: `GS` points to the per-CPU area, which starts with its own address.
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// Returns a unique identifying number of this processor.
+///
+/// Processors are numbered from zero in the order they appear in the MADT,
+/// the bootstrap processor is always zero.
+#[inline]
+pub fn id() -> u32 {
+    header().id
+}
+
+/// Like [`id`], but returns `None` if the per-CPU area of this processor is not
+/// installed yet.
+pub fn try_id() -> Option<u32> {
+    if GsBase::read().is_null() {
+        None
+    } else {
+        Some(id())
+    }
+}
+
+/// Returns the local APIC ID of this processor.
+#[inline]
+pub fn apic_id() -> u32 {
+    header().apic_id
+}
 
-const STEALER_INIT: Option<Stealer<TaskId>> = None;
-pub static mut STEALERS: [Option<Stealer<TaskId>>; MAX_NUM_CPUS] = [STEALER_INIT; MAX_NUM_CPUS];
+/// Returns the local APIC ID of the processor numbered `id`.
+pub fn apic_id_of(id: u32) -> u32 {
+    areas()[id as usize].header().apic_id
+}
 
-pub fn stealers<'a>() -> impl Iterator<Item = &'a Stealer<TaskId>> {
-    unsafe { STEALERS.iter().filter_map(|st| st.as_ref()) }
+/// Returns the number of processors that have a per-CPU area.
+pub fn count() -> u32 {
+    areas().len() as u32
 }
 
+/// Returns the ids of all processors that are online.
+pub fn online() -> impl Iterator<Item = u32> {
+    areas()
+        .iter()
+        .map(Area::header)
+        .filter(|header| header.online.load(Ordering::Acquire))
+        .map(|header| header.id)
+}
+
+pub fn stealers<'a>() -> impl Iterator<Item = &'a RunQueueStealers> {
+    STEALERS.get().into_iter().flat_map(|stealers| stealers.iter())
+}
+
+#[inline]
 pub fn cpu<'a>() -> &'a Cpu {
-    // SAFETY: the key guarantees unique access of the CPU.
-    CpuKey::scope(move |k| {
-        unsafe { &mut CPUS[k.num as usize] }.get_or_insert_with(|| {
-            let cpu = Box::leak(Box::new(Cpu::new()));
-            unsafe {
-                STEALERS[k.num as usize] = Some(cpu.worker.stealer());
-            }
-            cpu
-        })
-    })
+    // SAFETY: the area of this processor stays alive forever.
+    unsafe { &*header().cpu }
+}
+
+percpu! {
+    /// number of interrupt handlers running on this processor.
+    static IRQ_DEPTH: Cell<u32> = Cell::new(0);
+    /// whether an NMI handler is running on this processor.
+    static IN_NMI: Cell<bool> = Cell::new(false);
+}
+
+/// Marks this processor as running an interrupt handler until dropped.
+pub struct IrqContext {
+    _notsend: PhantomData<*const ()>,
+}
+
+impl Drop for IrqContext {
+    fn drop(&mut self) {
+        IRQ_DEPTH.get().update(|depth| depth - 1);
+    }
+}
+
+/// Called at the start of interrupt handlers, see [`in_irq`].
+#[inline]
+pub fn enter_irq() -> IrqContext {
+    IRQ_DEPTH.get().update(|depth| depth + 1);
+    IrqContext {
+        _notsend: PhantomData,
+    }
+}
+
+/// Returns whether this processor is running an interrupt handler.
+#[inline]
+pub fn in_irq() -> bool {
+    IRQ_DEPTH.get().get() != 0
+}
+
+/// Marks this processor as running an NMI handler until dropped.
+pub struct NmiContext {
+    _irq: IrqContext,
+}
+
+impl Drop for NmiContext {
+    fn drop(&mut self) {
+        IN_NMI.get().set(false);
+    }
+}
+
+/// Called at the start of the NMI handler, see [`in_nmi`].
+#[inline]
+pub fn enter_nmi() -> NmiContext {
+    let irq = enter_irq();
+    IN_NMI.get().set(true);
+    NmiContext { _irq: irq }
+}
+
+/// Returns whether this processor is running an NMI handler. NMIs interrupt
+/// even code that disabled interrupts, so the handler must not take locks
+/// that such code holds, including the one of the heap.
+#[inline]
+pub fn in_nmi() -> bool {
+    IN_NMI.get().get()
+}
+
+/// A set of processors, identified by their numbers.
+///
+/// Only the first 64 processors can be named individually, the others are
+/// only contained in [`CpuSet::all`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    pub const fn empty() -> CpuSet {
+        CpuSet(0)
+    }
+
+    pub const fn all() -> CpuSet {
+        CpuSet(u64::MAX)
+    }
+
+    /// Returns the set containing only the processor numbered `cpu`.
+    pub const fn single(cpu: u32) -> CpuSet {
+        assert!(cpu < u64::BITS, "processor number too large for a CpuSet");
+        CpuSet(1 << cpu)
+    }
+
+    #[inline]
+    pub fn insert(&mut self, cpu: u32) {
+        *self = self.union(CpuSet::single(cpu));
+    }
+
+    #[inline]
+    pub fn union(self, other: CpuSet) -> CpuSet {
+        CpuSet(self.0 | other.0)
+    }
+
+    #[inline]
+    pub fn contains(self, cpu: u32) -> bool {
+        if cpu < u64::BITS {
+            self.0 & 1 << cpu != 0
+        } else {
+            self == CpuSet::all()
+        }
+    }
+
+    #[inline]
+    pub fn is_all(self) -> bool {
+        self == CpuSet::all()
+    }
+
+    /// Returns the online processors in this set.
+    pub fn online(self) -> impl Iterator<Item = u32> {
+        online().filter(move |&cpu| self.contains(cpu))
+    }
+}
+
+impl FromIterator<u32> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> CpuSet {
+        let mut set = CpuSet::empty();
+        for cpu in iter {
+            set.insert(cpu);
+        }
+        set
+    }
+}
+
+/// A variable that every processor has its own instance of.
+///
+/// Declare these with [`percpu!`]. The declared static is only the initial value,
+/// which is copied bitwise into every per-CPU area, so the initializer must not
+/// own resources that cannot be duplicated.
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
+
+// SAFETY: every processor only accesses its own instance, unless `T: Sync`.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        assert!(align_of::<T>() <= PERCPU_ALIGN);
+        PerCpu {
+            template: UnsafeCell::new(value),
+        }
+    }
+
+    /// Offset of this variable from the start of the per-CPU data.
+    #[inline]
+    fn offset(&self) -> usize {
+        self.template.get() as usize - addr_of!(__percpu_start) as usize
+    }
+
+    /// Returns the instance of this processor.
+    #[inline]
+    pub fn get(&self) -> &T {
+        unsafe { &*header().data().add(self.offset()).cast() }
+    }
+
+    /// Returns the instance of the processor numbered `cpu`.
+    #[inline]
+    pub fn get_for(&self, cpu: u32) -> &T
+    where
+        T: Sync,
+    {
+        let header = areas()[cpu as usize].header();
+        unsafe { &*header.data().add(self.offset()).cast() }
+    }
+}
+
+/// Declares per-CPU variables.
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// TICKS.get().set(1);
+/// ```
+pub macro percpu($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)+) {
+    $(
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::cores::PerCpu<$t> = $crate::cores::PerCpu::new($init);
+    )+
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{id, online, percpu};
+    use crate::arch::ipi::{call_function, Destination};
+    use crate::testing::kernel_test;
+
+    percpu! {
+        static VALUE: AtomicU32 = AtomicU32::new(u32::MAX);
+    }
+
+    #[kernel_test]
+    fn get_is_get_for_self() {
+        assert!(core::ptr::eq(VALUE.get(), VALUE.get_for(id())));
+    }
+
+    #[kernel_test]
+    fn instances_are_separate() {
+        call_function(Destination::All, &|| {
+            VALUE.get().store(id(), Ordering::Relaxed);
+        });
+        for cpu in online() {
+            assert_eq!(VALUE.get_for(cpu).load(Ordering::Relaxed), cpu);
+        }
+    }
 }
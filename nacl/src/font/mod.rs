@@ -1,19 +1,19 @@
-use alloc::boxed::Box;
-use alloc::vec;
-use limine::framebuffer::Framebuffer;
+//! Printing to the screen, with the console of [`nacl_core`].
+
 use core::{fmt, slice};
 
-use hashbrown::HashMap;
-use x86_64::instructions::interrupts::without_interrupts;
+use limine::framebuffer::Framebuffer;
+pub use nacl_core::console::{Console, FrameBufferSink};
+use nacl_core::font::Font;
 
-use crate::task::lock::Mutex;
+use crate::task::lock::IrqSpinLock;
 
 static FT: &[u8] = include_bytes!("ter-u20n.psf");
 
-pub static FBMAN: Mutex<Option<FrameBufferManager>> = Mutex::new(None);
+pub static FBMAN: IrqSpinLock<Option<FrameBufferManager>> = IrqSpinLock::new(None);
 
 pub(crate) fn insert_fbman(fbman: FrameBufferManager) {
-    let mut guard = FBMAN.lock_or_spin();
+    let mut guard = FBMAN.lock();
     debug_assert!(guard.is_none());
 
     *guard = Some(fbman);
@@ -23,16 +23,12 @@ pub(crate) fn insert_fbman(fbman: FrameBufferManager) {
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
-    // avoid deadlocks by disabling interrupts before aquiring the lock,
-    // enabling interrupts after lock is released.
-    without_interrupts(|| {
-        FBMAN
-            .lock_or_spin()
-            .as_mut()
-            .expect("screen uninitialized")
-            .write_fmt(args)
-            .expect("Printing to screen failed");
-    });
+    FBMAN
+        .lock()
+        .as_mut()
+        .expect("screen uninitialized")
+        .write_fmt(args)
+        .expect("Printing to screen failed");
 }
 
 /// Prints to the screen
@@ -53,63 +49,14 @@ macro_rules! println {
     ));
 }
 
-pub struct PsfError;
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct PsfHeader {
-    magic: u32,
-    version: u32,
-    headersize: u32,
-    flags: u32,
-    glyphs: u32,
-    bytes_per_glyph: u32,
-    height: u32,
-    width: u32,
-}
-
-impl PsfHeader {
-    pub fn ft() -> &'static Self {
-        unsafe { FT.as_ptr().cast::<Self>().as_ref().unwrap() }
-    }
-
-    fn unicode_mapping(&self) -> Option<HashMap<char, u32>> {
-        if self.flags == 0 {
-            return None;
-        }
-
-        let mut s = (self.headersize + self.glyphs * self.bytes_per_glyph) as usize;
-        let mut start = s;
-        let mut glyph = 0;
-        let mut map = HashMap::new();
-        while s < FT.len() {
-            if FT[s] == 0xFF {
-                let string = core::str::from_utf8(&FT[start..s]).expect("valid utf-8");
-                for ch in string.chars() {
-                    map.insert(ch, glyph);
-                }
-
-                glyph += 1;
-                s += 1;
-                start = s;
-                continue;
-            }
-
-            s += 1;
-        }
-
-        Some(map)
-    }
-}
-
-pub struct FrameBufferManager {
+/// The framebuffer, as a [`FrameBufferSink`] for the console.
+pub struct Screen {
     fb: &'static mut [u8],
-    mapping: Option<HashMap<char, u32>>,
-    pub chars: Box<[char]>,
-    pub horiz_chars: usize,
+    font: Font<'static>,
+    columns: usize,
+    rows: usize,
     pub bytes_per_pixel: usize,
     pub stride: usize,
-    idx: usize,
     /// Shift of the red mask in RGB.
     pub red_mask_shift: u8,
     /// Shift of the green mask in RGB.
@@ -118,13 +65,12 @@ pub struct FrameBufferManager {
     pub blue_mask_shift: u8,
 }
 
-impl fmt::Debug for FrameBufferManager {
+impl fmt::Debug for Screen {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("FrameBufferManager")
-            .field("horiz_chars", &self.horiz_chars)
+        f.debug_struct("Screen")
+            .field("font", &self.font)
             .field("bytes_per_pixel", &self.bytes_per_pixel)
             .field("stride", &self.stride)
-            .field("idx", &self.idx)
             .field("red_mask_shift", &self.red_mask_shift)
             .field("green_mask_shift", &self.green_mask_shift)
             .field("blue_mask_shift", &self.blue_mask_shift)
@@ -132,188 +78,58 @@ impl fmt::Debug for FrameBufferManager {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Color {
-    pub r: u32,
-    pub g: u32,
-    pub b: u32,
-}
+pub type FrameBufferManager = Console<Screen>;
 
-impl FrameBufferManager {
+impl Screen {
     pub fn new(b: &Framebuffer<'_>) -> Self {
-        let font = PsfHeader::ft();
-
-        let mapping = font.unicode_mapping();
-
-        let horiz_res = b.width() as usize;
-        let horiz_chars = horiz_res / font.width as usize;
-
-        let vert_res = b.height() as usize;
-        let vert_chars = vert_res / font.height as usize;
-
-        let chars = vec![' '; horiz_chars * vert_chars].into_boxed_slice();
+        let font = Font::parse(FT).expect("invalid built-in font");
 
         let bytes_per_pixel = (b.bpp() / 8) as usize;
+        assert_eq!(4, bytes_per_pixel);
         let stride = b.pitch() as usize;
 
         let fb = unsafe { slice::from_raw_parts_mut(b.addr(), b.height() as usize * stride) };
 
         Self {
             fb,
-            mapping,
-            chars,
-            horiz_chars,
+            columns: b.width() as usize / font.width(),
+            rows: b.height() as usize / font.height(),
+            font,
             bytes_per_pixel,
             stride,
-            idx: 0,
             red_mask_shift: b.red_mask_shift(),
             green_mask_shift: b.green_mask_shift(),
             blue_mask_shift: b.blue_mask_shift(),
         }
     }
+}
 
-    pub fn put(&mut self, c: char) {
-        if c == '\n' {
-            self.idx = 0;
-            self.newline();
-            self.redraw();
-            return;
-        }
-
-        let last_line = self.chars.len() - self.horiz_chars;
-
-        if self.idx == self.horiz_chars {
-            // content wraps to the next line
-            self.idx = 0;
-            self.newline();
-            let offset = last_line;
-            self.chars[offset] = c;
-            self.redraw()
-        } else {
-            let offset = last_line + self.idx;
-            self.chars[offset] = c;
-            self.putchar(
-                c,
-                self.idx,
-                self.chars.len() / self.horiz_chars - 1,
-                0xFFFFFF,
-                0,
-            );
-        }
-
-        self.idx += 1;
-    }
-
-    /// Redraw the whole grid.
-    fn redraw(&mut self) {
-        let mut x = 0;
-        let mut y = 0;
-        let horiz_chars = self.horiz_chars;
-        for &c in self.chars.as_ref() {
-            Self::putc(
-                self.fb,
-                self.bytes_per_pixel,
-                self.stride,
-                &self.mapping,
-                c,
-                x,
-                y,
-                u32::MAX,
-                0,
-            );
-            if x + 1 == horiz_chars {
-                y += 1;
-                x = 0;
-            } else {
-                x += 1;
-            }
-        }
+impl FrameBufferSink for Screen {
+    fn columns(&self) -> usize {
+        self.columns
     }
 
-    #[inline]
-    fn newline(&mut self) {
-        self.chars.rotate_left(self.horiz_chars);
-        let len = self.chars.len();
-        self.chars[len - self.horiz_chars..].fill(' ');
+    fn rows(&self) -> usize {
+        self.rows
     }
 
-    fn putc(
-        fb: &mut [u8],
-        bytes_per_pixel: usize,
-        stride: usize,
-        mapping: &Option<HashMap<char, u32>>,
-        c: char,
-        cx: usize,
-        cy: usize,
-        fg: u32,
-        bg: u32,
-    ) {
-        assert_eq!(4, bytes_per_pixel);
-
-        let font = PsfHeader::ft();
-        let font_height = font.height as usize;
-        let font_width = font.width as usize;
-        let bytes_per_line = (font_width + 7) / 8;
-        let c = mapping
-            .as_ref()
-            .map_or(c as u32, |m| m.get(&c).copied().unwrap_or(0));
-
-        // If there is no glyph for the character, we will display the first glyph.
-        let glyph_index = if c >= font.glyphs { 0 } else { c as usize };
+    fn draw_char(&mut self, c: char, cx: usize, cy: usize, fg: u32, bg: u32) {
+        let glyph = self.font.glyph(c);
+        let font_height = self.font.height();
+        let font_width = self.font.width();
+        let mut offset = (cy * font_height * self.stride) + (cx * font_width * self.bytes_per_pixel);
 
-        let mut glyph = unsafe {
-            FT.as_ptr()
-                .add(font.headersize as usize)
-                .add(glyph_index * font.bytes_per_glyph as usize)
-        };
-
-        let mut offset = (cy * font_height * stride) + (cx * font_width * bytes_per_pixel);
-
-        for _ in 0..font_height {
+        for y in 0..font_height {
             let mut line = offset;
-            let mut mask = 1 << (font_width - 1);
-
-            let gly = unsafe { (*glyph.cast::<u16>()).rotate_left(1) };
-
-            for _ in 0..font_width {
+            for x in 0..font_width {
+                let color = if glyph.pixel(x, y) { fg } else { bg };
                 unsafe {
-                    let pixel = fb.as_mut_ptr().add(line) as *mut u32;
-                    pixel.write_volatile(if gly & mask != 0 { fg } else { bg });
+                    let pixel = self.fb.as_mut_ptr().add(line) as *mut u32;
+                    pixel.write_volatile(color);
                 }
-                mask >>= 1;
-                line += bytes_per_pixel;
-            }
-            unsafe {
-                glyph = glyph.add(bytes_per_line);
+                line += self.bytes_per_pixel;
             }
-            offset += stride;
-        }
-    }
-
-    pub fn putchar(&mut self, c: char, cx: usize, cy: usize, fg: u32, bg: u32) {
-        Self::putc(
-            self.fb,
-            self.bytes_per_pixel,
-            self.stride,
-            &self.mapping,
-            c,
-            cx,
-            cy,
-            fg,
-            bg,
-        )
-    }
-}
-
-impl core::fmt::Write for FrameBufferManager {
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        self.put(c);
-        Ok(())
-    }
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.chars() {
-            self.put(c)
+            offset += self.stride;
         }
-        Ok(())
     }
 }
@@ -7,6 +7,9 @@
 #![feature(fn_align)]
 #![feature(type_alias_impl_trait)]
 #![feature(naked_functions)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 #![allow(clippy::missing_safety_doc)] // TODO remove this later
@@ -14,10 +17,14 @@
 extern crate alloc;
 
 pub mod arch;
+pub mod console;
 pub mod cores;
 pub mod font;
 pub mod serial;
+pub mod shutdown;
 pub mod task;
+#[cfg(test)]
+pub mod testing;
 
 use core::panic::PanicInfo;
 
@@ -27,7 +34,7 @@ use log::Log;
 use x86_64::VirtAddr;
 
 use crate::cores::cpu;
-use crate::font::FrameBufferManager;
+use crate::font::{FrameBufferManager, FrameBufferSink, Screen};
 
 #[repr(C, align(4096))]
 pub struct PageAligned<T>(pub T);
@@ -87,15 +94,24 @@ pub extern "C" fn kernel_start() -> ! {
 
     init();
 
+    #[cfg(test)]
+    test_main();
+
     sprintln!("ok");
 
     println!("NaCl v{}", env!("CARGO_PKG_VERSION"));
     println!("Ayo");
 
+    task::executor::spawn_task(
+        task::Task::new(console::run())
+            .with_name("console")
+            .with_priority(task::priority::Priority::Interactive),
+    );
+
     /*for i in 1..1000 {
-        cpu().executor.borrow_mut().spawn(task::Task::new(async move {
-            FBMAN.lock().await.as_mut().unwrap().write_fmt(format_args_nl!("{i}")).unwrap();
-        }))
+        task::spawn(async move {
+            FBMAN.lock().as_mut().unwrap().write_fmt(format_args_nl!("{i}")).unwrap();
+        });
     }*/
     cpu().executor.borrow_mut().run();
 }
@@ -128,18 +144,27 @@ fn init() {
     // log::info!("hi");
 
     let frame_buffer = FRAMEBUFFER_REQUEST.get_response().unwrap().framebuffers().next().unwrap();
-    let mut fb = FrameBufferManager::new(&frame_buffer);
+    let mut fb = FrameBufferManager::new(Screen::new(&frame_buffer));
     sprintln!("{fb:?}");
-    fb.putchar('F', 0, 0, 0xFFFFFF, 0);
+    fb.sink_mut().draw_char('F', 0, 0, 0xFFFFFF, 0);
     font::insert_fbman(fb);
 
     // initialize per-core memory access.
     crate::arch::init(physical_memory_offset as usize, RSDP_REQUEST.get_response().unwrap().address() as usize - physical_memory_offset as usize);
+
+    // the executor runs on the boot thread.
+    task::thread::init("executor");
 }
 
 #[panic_handler]
 pub fn panic_handler(info: &PanicInfo) -> ! {
-    sprintln!("{}", info);
+    // the panic may have happened while printing.
+    emergency_sprintln!("{}", info);
+    // continues after the poll of the panicking task, if there is one.
+    task::panic::throw(info);
+    #[cfg(test)]
+    testing::uncaught_panic();
+    #[cfg(not(test))]
     hlt_loop()
 }
 
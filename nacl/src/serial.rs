@@ -1,20 +1,43 @@
+use core::future::poll_fn;
 use core::sync::atomic::AtomicBool;
+use core::task::Poll;
 
+use futures_util::task::AtomicWaker;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly};
 
-use crate::task::lock::{Mutex, MutexGuard};
+use crate::task::lock::{IrqSpinLock, IrqSpinLockGuard};
 
-static SERIAL1: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(0x3F8) });
+/// I/O port base of the first serial port.
+const SERIAL1_BASE: u16 = 0x3F8;
+
+static SERIAL1: IrqSpinLock<SerialPort> =
+    IrqSpinLock::new(unsafe { SerialPort::new(SERIAL1_BASE) });
 static INIT: AtomicBool = AtomicBool::new(false);
 
-fn serial1() -> MutexGuard<'static, SerialPort> {
+const INPUT_SIZE: usize = 256;
+
+/// Bytes received but not read yet.
+struct Input {
+    buf: [u8; INPUT_SIZE],
+    head: usize,
+    len: usize,
+}
+
+static INPUT: IrqSpinLock<Input> = IrqSpinLock::new(Input {
+    buf: [0; INPUT_SIZE],
+    head: 0,
+    len: 0,
+});
+static INPUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn serial1() -> IrqSpinLockGuard<'static, SerialPort> {
     if !INIT.swap(true, core::sync::atomic::Ordering::Relaxed) {
-        let mut guard = SERIAL1.lock_or_spin();
+        let mut guard = SERIAL1.lock();
         guard.init();
         guard
     } else {
-        SERIAL1.lock_or_spin()
+        SERIAL1.lock()
     }
 }
 
@@ -22,13 +45,70 @@ fn serial1() -> MutexGuard<'static, SerialPort> {
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
-    // avoid deadlocks by disabling interrupts before aquiring the lock,
-    // enabling interrupts after lock is released.
-    without_interrupts(|| {
-        serial1()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+    serial1()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Prints to the serial port even if the lock is held, used by NMI handlers
+/// which may have interrupted the holder. Output may interleave with other prints.
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _ = match SERIAL1.try_lock() {
+        Some(mut guard) => guard.write_fmt(args),
+        None => unsafe { SerialPort::new(SERIAL1_BASE) }.write_fmt(args),
+    };
+}
+
+/// Moves the received bytes into the input buffer, dropping them if it is full.
+///
+/// Called by the serial interrupt handler.
+pub fn receive_pending() {
+    let mut line_status = PortReadOnly::<u8>::new(SERIAL1_BASE + 5);
+    let mut data = Port::<u8>::new(SERIAL1_BASE);
+
+    let mut input = INPUT.lock();
+    // bit 0 of the line status register is set while data is available.
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        if input.len < INPUT_SIZE {
+            let tail = (input.head + input.len) % INPUT_SIZE;
+            input.buf[tail] = byte;
+            input.len += 1;
+        }
+    }
+    drop(input);
+
+    // the executor is told through its inbox, its run queues are not touched.
+    INPUT_WAKER.wake();
+}
+
+/// Waits for the next byte received on the serial port.
+pub async fn read_byte() -> u8 {
+    poll_fn(|cx| {
+        INPUT_WAKER.register(cx.waker());
+        let mut input = INPUT.lock();
+        if input.len == 0 {
+            return Poll::Pending;
+        }
+        let byte = input.buf[input.head];
+        input.head = (input.head + 1) % INPUT_SIZE;
+        input.len -= 1;
+        Poll::Ready(byte)
     })
+    .await
+}
+
+/// Waits until everything written to the serial port has been sent.
+pub fn flush() {
+    let _serial = serial1();
+    let mut line_status = PortReadOnly::<u8>::new(SERIAL1_BASE + 5);
+    // bit 6 of the line status register is set once the transmitter is empty.
+    while unsafe { line_status.read() } & 1 << 6 == 0 {
+        core::hint::spin_loop();
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -48,3 +128,9 @@ macro_rules! sprintln {
         format_args_nl!($fmt, $($arg)*)
     ));
 }
+
+/// Like `sprintln!`, but does not wait for other users of the serial port.
+#[macro_export]
+macro_rules! emergency_sprintln {
+    ($($arg:tt)*) => ($crate::serial::_emergency_print(format_args_nl!($($arg)*)));
+}
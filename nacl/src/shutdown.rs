@@ -0,0 +1,107 @@
+//! Shutting the kernel down.
+//!
+//! [`shutdown`] cancels [`token`], which long running tasks should watch. The
+//! executors keep running for a grace period so tasks can finish, then drop
+//! the tasks that are left. Once the executors of all processors are drained,
+//! the serial port is flushed and the machine powers off or reboots.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::arch::{self, ipi, tsc, tsc_to_duration};
+use crate::task::cancel::CancellationToken;
+use crate::{cores, serial, sprintln};
+
+/// How long tasks get to finish after the shutdown token is cancelled.
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    PowerOff = 1,
+    Reboot = 2,
+}
+
+/// The requested [`Action`], zero while running.
+static REQUESTED: AtomicU8 = AtomicU8::new(0);
+/// Time stamp counter when the shutdown was requested.
+static REQUESTED_AT: AtomicU64 = AtomicU64::new(0);
+/// Number of executors that are not drained.
+static EXECUTORS: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref TOKEN: CancellationToken = CancellationToken::new();
+}
+
+/// The token cancelled when the kernel shuts down. Tasks can wait on it, or on
+/// a [child](CancellationToken::child) of it.
+pub fn token() -> &'static CancellationToken {
+    &TOKEN
+}
+
+/// Starts shutting down, and returns. Only the first request counts.
+pub fn shutdown(action: Action) {
+    if REQUESTED
+        .compare_exchange(0, action as u8, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+    REQUESTED_AT.store(tsc(), Ordering::Release);
+    sprintln!("shutting down");
+    TOKEN.cancel();
+
+    // idle executors only notice the request when woken.
+    let me = cores::try_id();
+    for cpu in cores::online().filter(|&cpu| Some(cpu) != me) {
+        ipi::reschedule(cpu);
+    }
+}
+
+fn requested() -> Option<Action> {
+    match REQUESTED.load(Ordering::Acquire) {
+        0 => None,
+        1 => Some(Action::PowerOff),
+        _ => Some(Action::Reboot),
+    }
+}
+
+pub fn is_shutting_down() -> bool {
+    requested().is_some()
+}
+
+/// Called by every executor when it starts running.
+pub(crate) fn executor_started() {
+    EXECUTORS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Whether an executor with `tasks_left` should drop its tasks now.
+pub(crate) fn should_drain(tasks_left: bool) -> bool {
+    if !is_shutting_down() {
+        return false;
+    }
+    let elapsed = tsc().wrapping_sub(REQUESTED_AT.load(Ordering::Acquire));
+    !tasks_left || tsc_to_duration(elapsed) >= GRACE_PERIOD
+}
+
+/// Called by every executor once it dropped its tasks. The last one finishes
+/// the shutdown, the others halt.
+pub(crate) fn executor_drained() -> ! {
+    let action = requested().expect("executor drained without shutdown");
+    if EXECUTORS.fetch_sub(1, Ordering::AcqRel) != 1 {
+        loop {
+            interrupts::disable();
+            hlt();
+        }
+    }
+
+    sprintln!("all executors drained, {:?}", action);
+    serial::flush();
+    match action {
+        Action::PowerOff => arch::power_off(),
+        Action::Reboot => arch::reboot(),
+    }
+}
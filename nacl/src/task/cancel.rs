@@ -0,0 +1,156 @@
+//! Cancellation tokens.
+//!
+//! A [`CancellationToken`] is a shared flag that tasks check or await to learn
+//! that they should stop. Tokens form a tree: cancelling a token also cancels
+//! the tokens created with [`CancellationToken::child`], but not its parent.
+//! All tasks should stop once [`crate::shutdown::token`] is cancelled.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use super::combinator::{select, Either};
+use super::lock::IrqSpinLock;
+
+#[derive(Clone)]
+pub struct CancellationToken {
+    node: Arc<Node>,
+}
+
+struct Node {
+    cancelled: AtomicBool,
+    state: IrqSpinLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// wakers of the pending [`Cancelled`] futures, by their id.
+    waiters: BTreeMap<u64, Waker>,
+    next_waiter: u64,
+    children: Vec<Weak<Node>>,
+}
+
+impl Default for CancellationToken {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            node: Arc::new(Node {
+                cancelled: AtomicBool::new(false),
+                state: IrqSpinLock::new(State::default()),
+            }),
+        }
+    }
+
+    /// Returns a new token that is cancelled when this one is, and can also be
+    /// cancelled on its own.
+    pub fn child(&self) -> Self {
+        let child = Self::new();
+        let mut state = self.node.state.lock();
+        // `cancel` sets the flag before taking the children, so checking it
+        // with the lock held does not miss a concurrent cancel.
+        if self.is_cancelled() {
+            drop(state);
+            child.cancel();
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.node));
+        }
+        child
+    }
+
+    /// Cancels this token and its children, waking everything waiting for them.
+    pub fn cancel(&self) {
+        if self.node.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (waiters, children) = {
+            let mut state = self.node.state.lock();
+            (
+                mem::take(&mut state.waiters),
+                mem::take(&mut state.children),
+            )
+        };
+        for waker in waiters.into_values() {
+            waker.wake();
+        }
+        for child in children.iter().filter_map(Weak::upgrade) {
+            CancellationToken { node: child }.cancel();
+        }
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.node.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns a future that completes once this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled {
+            token: self,
+            waiter: None,
+        }
+    }
+
+    /// Runs `future` until it completes, or until this token is cancelled, in
+    /// which case `future` is dropped and `None` is returned.
+    pub async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        match select(self.cancelled(), future).await {
+            Either::Left(()) => None,
+            Either::Right(output) => Some(output),
+        }
+    }
+}
+
+/// Future returned by [`CancellationToken::cancelled`].
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+    /// the id of the registered waker.
+    waiter: Option<u64>,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        let node = &self.token.node;
+        let mut state = node.state.lock();
+        // checked again with the lock held, see `CancellationToken::child`.
+        if node.cancelled.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        let waiter = match self.waiter {
+            Some(waiter) => waiter,
+            None => {
+                let waiter = state.next_waiter;
+                state.next_waiter += 1;
+                waiter
+            }
+        };
+        state.waiters.insert(waiter, cx.waker().clone());
+        drop(state);
+        self.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter {
+            self.token.node.state.lock().waiters.remove(&waiter);
+        }
+    }
+}
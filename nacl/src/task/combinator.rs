@@ -0,0 +1,218 @@
+//! Running several futures concurrently within one task.
+//!
+//! The futures are polled by the task awaiting the combinator, so they may
+//! borrow from it, and they only run on the processor of that task. Use
+//! [`Scope`](super::scope::Scope) for a changing number of futures, or spawn
+//! tasks to make use of other processors.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::mem;
+use core::pin::{pin, Pin};
+use core::task::{Context, Poll};
+
+/// The output of [`select`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// A future that keeps its output around once it is ready.
+#[doc(hidden)]
+pub enum MaybeDone<F: Future> {
+    Future(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    pub fn new(future: F) -> Self {
+        MaybeDone::Future(future)
+    }
+
+    /// Takes the output, if the future is done.
+    pub fn take_output(self: Pin<&mut Self>) -> Option<F::Output> {
+        // SAFETY: nothing is moved unless the future is already gone.
+        let this = unsafe { self.get_unchecked_mut() };
+        match this {
+            MaybeDone::Done(_) => match mem::replace(this, MaybeDone::Taken) {
+                MaybeDone::Done(output) => Some(output),
+                _ => unreachable!(),
+            },
+            _ => None,
+        }
+    }
+}
+
+impl<F: Future> Future for MaybeDone<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: the future is not moved, it is dropped in place once done.
+        let this = unsafe { self.get_unchecked_mut() };
+        if let MaybeDone::Future(future) = this {
+            match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                Poll::Ready(output) => *this = MaybeDone::Done(output),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Waits for both futures, polling them concurrently.
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(MaybeDone::new(a));
+    let mut b = pin!(MaybeDone::new(b));
+    poll_fn(|cx| {
+        let a = a.as_mut().poll(cx);
+        let b = b.as_mut().poll(cx);
+        if a.is_ready() && b.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    (a.take_output().unwrap(), b.take_output().unwrap())
+}
+
+/// Waits for all `futures`, polling them concurrently. The outputs are in the
+/// same order as the futures.
+pub async fn join_all<I>(futures: I) -> Vec<<I::Item as Future>::Output>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futures: Box<[_]> = futures.into_iter().map(MaybeDone::new).collect();
+    let mut futures = Box::into_pin(futures);
+    poll_fn(|cx| {
+        let mut ready = true;
+        for future in iter_pin_mut(futures.as_mut()) {
+            ready &= future.poll(cx).is_ready();
+        }
+        if ready {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    iter_pin_mut(futures.as_mut())
+        .map(|future| future.take_output().unwrap())
+        .collect()
+}
+
+fn iter_pin_mut<T>(slice: Pin<&mut [T]>) -> impl Iterator<Item = Pin<&mut T>> {
+    // SAFETY: the elements of a pinned slice are pinned too.
+    unsafe { slice.get_unchecked_mut() }
+        .iter_mut()
+        .map(|element| unsafe { Pin::new_unchecked(element) })
+}
+
+/// Waits for the first of two futures and drops the other one.
+///
+/// `a` is polled first, so it wins if both are ready.
+pub async fn select<A: Future, B: Future>(a: A, b: B) -> Either<A::Output, B::Output> {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Right(output));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Like [`select`], for futures with the same output.
+pub async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    match select(a, b).await {
+        Either::Left(output) | Either::Right(output) => output,
+    }
+}
+
+/// Waits for all futures, polling them concurrently, and returns a tuple of
+/// their outputs. Must be used in async code.
+///
+/// ```ignore
+/// let (a, b, c) = join!(read(0), read(1), read(2));
+/// ```
+pub macro join {
+    (@{ ($($count:tt)*) $(($($skip:tt)*) $future:expr,)* }) => {{
+        let mut futures = ::core::pin::pin!(($($crate::task::combinator::MaybeDone::new($future),)*));
+        ::core::future::poll_fn(|cx| {
+            let mut ready = true;
+            $(
+                // SAFETY: the tuple is pinned, so its fields are too.
+                let ($($skip,)* future, ..) = unsafe { futures.as_mut().get_unchecked_mut() };
+                let future = unsafe { ::core::pin::Pin::new_unchecked(future) };
+                ready &= ::core::future::Future::poll(future, cx).is_ready();
+            )*
+            if ready {
+                ::core::task::Poll::Ready(())
+            } else {
+                ::core::task::Poll::Pending
+            }
+        })
+        .await;
+        ($({
+            let ($($skip,)* future, ..) = unsafe { futures.as_mut().get_unchecked_mut() };
+            unsafe { ::core::pin::Pin::new_unchecked(future) }.take_output().unwrap()
+        },)*)
+    }},
+    // counts the futures, so that each can be picked out of the tuple.
+    (@{ ($($count:tt)*) $($done:tt)* } $future:expr, $($rest:tt)*) => {
+        $crate::task::combinator::join!(@{ ($($count)* _) $($done)* ($($count)*) $future, } $($rest)*)
+    },
+    ($($future:expr),+ $(,)?) => {
+        $crate::task::combinator::join!(@{ () } $($future,)+)
+    },
+}
+
+/// Waits for the first of several futures and runs its handler with the
+/// output bound to its pattern. The other futures are dropped. Must be used in
+/// async code.
+///
+/// Futures are polled in order, so earlier ones win if several are ready.
+/// Patterns must be irrefutable. Handlers run in the enclosing function, so
+/// they may `.await`, `return` or `break`.
+///
+/// ```ignore
+/// select! {
+///     byte = serial::read_byte() => handle_input(byte),
+///     result = worker => return result,
+/// }
+/// ```
+pub macro select {
+    (@future $pat:pat = $future:expr => $handler:expr) => {
+        $future
+    },
+    (@future $pat:pat = $future:expr => $handler:expr, $($rest:tt)+) => {
+        $crate::task::combinator::select($future, $crate::task::combinator::select!(@future $($rest)+))
+    },
+    (@match $output:expr; $pat:pat = $future:expr => $handler:expr) => {
+        match $output {
+            $pat => $handler,
+        }
+    },
+    (@match $output:expr; $pat:pat = $future:expr => $handler:expr, $($rest:tt)+) => {
+        match $output {
+            $crate::task::combinator::Either::Left($pat) => $handler,
+            $crate::task::combinator::Either::Right(rest) => {
+                $crate::task::combinator::select!(@match rest; $($rest)+)
+            }
+        }
+    },
+    ($($pat:pat = $future:expr => $handler:expr),+ $(,)?) => {
+        $crate::task::combinator::select!(
+            @match $crate::task::combinator::select!(@future $($pat = $future => $handler),+).await;
+            $($pat = $future => $handler),+
+        )
+    },
+}
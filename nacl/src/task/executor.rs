@@ -1,14 +1,151 @@
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use core::cell::Cell;
+use core::future::Future;
 use core::task::{Context, Poll, Waker};
 
-use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use nacl_core::executor::CpuId;
 
-use super::{Task, TaskId};
-use crate::cores::{cpu, stealers};
+use super::info::{self, TaskInfo};
+use super::join::{JoinError, JoinHandle};
+use super::lock::IrqSpinLock;
+use super::map::ConcurrentMap;
+use super::priority::Priority;
+use super::{panic, rcu, thread, Task, TaskId};
+use crate::arch::ipi;
+use crate::cores::{self, cpu, percpu, stealers, CpuSet};
+use crate::{emergency_sprintln, shutdown};
 
+/// Work handed to an executor by other processors.
+enum Message {
+    Spawn(Task),
+    Wake(TaskId),
+}
+
+percpu! {
+    static INBOX: IrqSpinLock<VecDeque<Message>> = IrqSpinLock::new(VecDeque::new());
+    /// the thread running the executor of this processor.
+    static EXECUTOR_THREAD: Cell<Option<thread::ThreadId>> = Cell::new(None);
+}
+
+/// Whether this runs on the executor thread of this processor, outside of
+/// interrupt handlers. The run queues have a single owner, and only there may
+/// they be used directly.
+fn on_executor_thread() -> bool {
+    !cores::in_irq()
+        && thread::try_current()
+            .is_some_and(|thread| EXECUTOR_THREAD.get().get() == Some(thread.id()))
+}
+
+/// Sends `message` to the executor of the processor numbered `cpu`.
+fn send(cpu: u32, message: Message) {
+    INBOX.get_for(cpu).lock().push_back(message);
+    if cores::try_id() != Some(cpu) {
+        ipi::reschedule(cpu);
+    }
+}
+
+/// Spawns `future` on the processor numbered `cpu`, where it always runs.
+#[track_caller]
+pub fn spawn_on<F>(cpu: u32, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = Task::joinable(future);
+    send(cpu, Message::Spawn(task.with_affinity(CpuSet::single(cpu))));
+    handle
+}
+
+/// Spawns `task` on this processor, or on another one if this processor is
+/// not in the affinity of the task.
+///
+/// The task is sent through the inbox of the executor, which is never borrowed,
+/// so this can be called from within a task or from another thread.
+pub fn spawn_task(task: Task) {
+    send(home_cpu(&task), Message::Spawn(task));
+}
+
+/// The processor `task` is spawned on: this one if the affinity of the task
+/// allows, the first online one in it otherwise.
+fn home_cpu(task: &Task) -> u32 {
+    let me = cores::id();
+    if task.affinity().contains(me) {
+        me
+    } else {
+        task.affinity()
+            .online()
+            .next()
+            .expect("no online processor in task affinity")
+    }
+}
+
+lazy_static! {
+    /// Every spawned task that did not finish yet, shared by all executors so
+    /// that they can poll the tasks they steal from each other.
+    static ref TASKS: ConcurrentMap<TaskId, Arc<Slot>> = ConcurrentMap::with_buckets(1024);
+}
+
+/// The processor numbers of [`cores`].
+struct Cores;
+
+impl CpuId for Cores {
+    #[inline]
+    fn current() -> Option<u32> {
+        cores::try_id()
+    }
+}
+
+/// A task in [`TASKS`].
+struct Slot {
+    info: Arc<TaskInfo>,
+    waker: Waker,
+    state: nacl_core::executor::Slot<Task, Cores>,
+}
+
+impl Slot {
+    /// Takes ownership of the task, or makes the executor that owns it queue it
+    /// again once it is done.
+    #[inline]
+    fn try_acquire(&self) -> bool {
+        self.state.try_acquire()
+    }
+
+    fn release(&self) {
+        if self.state.release() {
+            let info = &self.info;
+            push(info.id(), info.priority(), !info.affinity().is_all());
+        }
+    }
+
+    /// Returns the task, `None` if it finished.
+    ///
+    /// # Safety
+    ///
+    /// The task must be acquired, and the reference must not outlive the
+    /// ownership.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn task(&self) -> &mut Option<Task> {
+        self.state.task()
+    }
+
+    /// Takes the task out of the slot and removes the slot from [`TASKS`].
+    ///
+    /// The task must be acquired.
+    fn finish(&self) -> Option<Task> {
+        // SAFETY: the task is acquired.
+        let task = unsafe { self.state.take() };
+        TASKS.remove(&self.info.id());
+        info::unregister(self.state.home(), self.info.id());
+        task
+    }
+}
+
+/// Runs the tasks of one processor. The tasks themselves are in a global table,
+/// so executors can poll the tasks they steal from each other.
 pub struct Executor {
-    tasks: HashMap<TaskId, Task>,
-    waker_cache: HashMap<TaskId, Waker>,
+    _private: (),
 }
 
 impl Default for Executor {
@@ -21,79 +158,183 @@ impl Default for Executor {
 impl Executor {
     #[inline]
     pub fn new() -> Self {
-        Executor {
-            tasks: HashMap::new(),
-            waker_cache: HashMap::new(),
-        }
+        Executor { _private: () }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+    /// Registers and queues `task`, or sends it to the processor it belongs to.
+    ///
+    /// Only the executor itself spawns this way, [`spawn_task`] works anywhere.
+    fn spawn(&mut self, task: Task) {
+        let cpu = home_cpu(&task);
+        if cpu != cores::id() {
+            send(cpu, Message::Spawn(task));
+            return;
+        }
+
+        let task_id = task.id();
+        let priority = task.priority();
+        let pinned = task.is_pinned();
+        let info = task.info().clone();
+        info::register(&info);
+        let slot = Slot {
+            waker: TaskWaker::new_waker(info.clone()),
+            info,
+            state: nacl_core::executor::Slot::new(task),
+        };
+        if TASKS.insert(task_id, Arc::new(slot)).is_err() {
             panic!("task with same ID already in tasks");
         }
-        cpu().worker.push(task_id);
+        push(task_id, priority, pinned);
+    }
+
+    fn receive(&mut self) {
+        loop {
+            // do not hold the lock while spawning, which may send messages.
+            let message = INBOX.get().lock().pop_front();
+            match message {
+                Some(Message::Spawn(task)) => self.spawn(task),
+                Some(Message::Wake(task_id)) => {
+                    if let Some(slot) = TASKS.get_cloned(&task_id) {
+                        let info = &slot.info;
+                        push(task_id, info.priority(), !info.affinity().is_all());
+                    }
+                }
+                None => break,
+            }
+        }
     }
 
     fn run_ready_tasks(&mut self) {
-        let Self { tasks, waker_cache } = self;
-        while let Some(task_id) = cpu().worker.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+        self.receive();
+
+        let me = thread::current();
+        while let Some(task_id) = cpu().run_queues.pop() {
+            let Some(slot) = TASKS.get_cloned(&task_id) else {
+                continue; // task no longer exists
+            };
+            if !slot.try_acquire() {
+                continue; // polled by another processor, which queues it again
+            }
+            // SAFETY: the task is acquired.
+            let Some(task) = (unsafe { slot.task() }) else {
+                slot.release();
+                continue; // finished while it was queued
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+
+            let mut context = Context::from_waker(&slot.waker);
+            let result = panic::catch(|| task.poll(&mut context, &me));
+            // reset outside of `catch`, a panic skips everything inside it.
+            me.locals.set(None);
+            match result {
+                Ok(Poll::Ready(())) => {
+                    let task = slot.finish();
+                    slot.release();
+                    drop(task);
+                }
+                Ok(Poll::Pending) => slot.release(),
+                Err(message) => {
+                    // the task may have panicked while printing.
+                    emergency_sprintln!(
+                        "task {} ({}) panicked: {}",
+                        task_id,
+                        slot.info.name(),
+                        message
+                    );
+                    let task = slot.finish().unwrap();
+                    slot.release();
+                    task.abandon(JoinError::Panicked(message));
                 }
-                Poll::Pending => {}
             }
         }
     }
 
     pub fn run(&mut self) -> ! {
+        EXECUTOR_THREAD.get().set(Some(thread::current().id()));
+        shutdown::executor_started();
         loop {
             self.run_ready_tasks();
+            // no task is running, so nothing is in an RCU critical section.
+            rcu::quiescent();
+            if shutdown::should_drain(info::any_on(cores::id())) {
+                self.drain();
+            }
             self.sleep_if_idle();
         }
     }
 
+    /// Drops the tasks spawned on this processor, including the ones sent to
+    /// it, when the kernel shuts down.
+    fn drain(&mut self) -> ! {
+        loop {
+            let message = INBOX.get().lock().pop_front();
+            match message {
+                Some(Message::Spawn(task)) => task.abort(JoinError::Cancelled),
+                Some(Message::Wake(_)) => {}
+                None => break,
+            }
+        }
+        for info in info::tasks_on(cores::id()) {
+            let Some(slot) = TASKS.get_cloned(&info.id()) else {
+                continue;
+            };
+            // another processor may still be polling a task it stole.
+            while !slot.try_acquire() {
+                core::hint::spin_loop();
+            }
+            // not released, the slot is gone anyway.
+            if let Some(task) = slot.finish() {
+                task.abort(JoinError::Cancelled);
+            }
+        }
+        shutdown::executor_drained()
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if cpu().worker.is_empty() {
-            if false {
-                panic!();
-            }
-            if !stealers().any(|stealer| {
-                let mut res = stealer.steal_batch(&cpu().worker);
-                while res.is_retry() {
-                    res = stealer.steal_batch(&cpu().worker);
-                }
-                res.is_success()
-            }) {
-                enable_and_hlt();
-            }
+        let inbox_empty = INBOX.get().lock().is_empty();
+        if inbox_empty
+            && cpu().run_queues.is_empty()
+            // pinned tasks are never in the queues stealers look at.
+            && !stealers().any(|stealer| stealer.steal_into(&cpu().run_queues))
+            // give other threads on this processor a chance before halting.
+            && !thread::yield_now()
+        {
+            enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
 }
 
+/// Queues a task of this processor.
+fn push(task_id: TaskId, priority: Priority, pinned: bool) {
+    let queues = &cpu().run_queues;
+    if pinned {
+        queues.push_pinned(task_id, priority);
+    } else {
+        queues.push(task_id, priority);
+    }
+}
+
 struct TaskWaker {
-    task_id: TaskId,
+    info: Arc<TaskInfo>,
+    /// the processor whose executor owns the task.
+    cpu: u32,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        cpu().worker.push(self.task_id);
+        let info = &self.info;
+        info.record_wake();
+        // interrupt handlers and other threads may interrupt the executor while
+        // it uses the run queues, so they go through the inbox.
+        if cores::try_id() == Some(self.cpu) && on_executor_thread() {
+            push(info.id(), info.priority(), !info.affinity().is_all());
+        } else {
+            send(self.cpu, Message::Wake(info.id()));
+        }
     }
 }
 
@@ -110,7 +351,10 @@ impl Wake for TaskWaker {
 }
 
 impl TaskWaker {
-    fn new_waker(task_id: TaskId) -> Waker {
-        Waker::from(Arc::new(TaskWaker { task_id }))
+    fn new_waker(info: Arc<TaskInfo>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            info,
+            cpu: cores::id(),
+        }))
     }
 }
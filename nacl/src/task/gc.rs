@@ -4,7 +4,12 @@
 //! is registered in the default collector.  If initialized, the thread's participant will get
 //! destructed on thread exit, which in turn unregisters the thread.
 
+use core::borrow::Borrow;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+
 use crossbeam_epoch::{Collector, Guard, LocalHandle};
+use x86_64::instructions::interrupts::{self, without_interrupts};
 
 use crate::cores::cpu;
 
@@ -13,16 +18,57 @@ lazy_static::lazy_static! {
     static ref COLLECTOR: Collector = Collector::new();
 }
 
-/// Pins the current thread.
+/// A pinned processor.
+///
+/// The participant of a processor is not safe to use from an interrupt handler
+/// or another thread while it is in use, so interrupts stay disabled until the
+/// guard is dropped.
+pub struct PinGuard {
+    guard: ManuallyDrop<Guard>,
+    /// whether interrupts were enabled before pinning.
+    interrupts_enabled: bool,
+}
+
+impl Deref for PinGuard {
+    type Target = Guard;
+
+    fn deref(&self) -> &Guard {
+        &self.guard
+    }
+}
+
+impl Borrow<Guard> for PinGuard {
+    fn borrow(&self) -> &Guard {
+        &self.guard
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        // SAFETY: the guard is not used again.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Pins the current processor, with interrupts disabled until the guard is
+/// dropped.
 #[inline]
-pub fn pin() -> Guard {
-    with_handle(|handle| handle.pin())
+pub fn pin() -> PinGuard {
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    PinGuard {
+        guard: ManuallyDrop::new(with_handle(|handle| handle.pin())),
+        interrupts_enabled,
+    }
 }
 
-/// Returns `true` if the current thread is pinned.
+/// Returns `true` if the current processor is pinned.
 #[inline]
 pub fn is_pinned() -> bool {
-    with_handle(|handle| handle.is_pinned())
+    without_interrupts(|| with_handle(|handle| handle.is_pinned()))
 }
 
 /// Returns the default global collector.
@@ -0,0 +1,127 @@
+//! Task introspection.
+//!
+//! Every task carries a [`TaskInfo`] with its name, where it was spawned and
+//! counters that the executor updates on every poll and wake. The executor of
+//! each processor registers the tasks spawned on it, so they can be listed with
+//! [`tasks_on`], even while other processors steal them.
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::lock::IrqSpinLock;
+use super::priority::Priority;
+use super::TaskId;
+use crate::arch::tsc_to_duration;
+use crate::cores::{percpu, CpuSet};
+
+pub struct TaskInfo {
+    pub(super) id: TaskId,
+    pub(super) name: Cow<'static, str>,
+    pub(super) priority: Priority,
+    pub(super) affinity: CpuSet,
+    pub(super) spawned_at: &'static Location<'static>,
+    polls: AtomicU64,
+    /// time stamp counter cycles spent in polls.
+    poll_cycles: AtomicU64,
+    max_poll_cycles: AtomicU64,
+    wakes: AtomicU64,
+}
+
+impl TaskInfo {
+    pub(super) fn new(spawned_at: &'static Location<'static>) -> TaskInfo {
+        TaskInfo {
+            id: TaskId::new(),
+            name: Cow::Borrowed("unnamed"),
+            priority: Priority::default(),
+            affinity: CpuSet::all(),
+            spawned_at,
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            max_poll_cycles: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    #[inline]
+    pub fn affinity(&self) -> CpuSet {
+        self.affinity
+    }
+
+    /// Where the task was created.
+    #[inline]
+    pub fn spawned_at(&self) -> &'static Location<'static> {
+        self.spawned_at
+    }
+
+    pub fn polls(&self) -> u64 {
+        self.polls.load(Ordering::Relaxed)
+    }
+
+    pub fn wakes(&self) -> u64 {
+        self.wakes.load(Ordering::Relaxed)
+    }
+
+    /// Total time spent polling the task.
+    pub fn busy(&self) -> Duration {
+        tsc_to_duration(self.poll_cycles.load(Ordering::Relaxed))
+    }
+
+    /// The longest time a single poll took.
+    pub fn max_poll(&self) -> Duration {
+        tsc_to_duration(self.max_poll_cycles.load(Ordering::Relaxed))
+    }
+
+    pub(super) fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_poll_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_wake(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+percpu! {
+    /// the live tasks spawned on this processor.
+    static TASKS: IrqSpinLock<BTreeMap<TaskId, Arc<TaskInfo>>> = IrqSpinLock::new(BTreeMap::new());
+}
+
+pub(super) fn register(info: &Arc<TaskInfo>) {
+    TASKS.get().lock().insert(info.id, info.clone());
+}
+
+/// Unregisters a task spawned on the processor numbered `cpu`.
+pub(super) fn unregister(cpu: u32, id: TaskId) {
+    TASKS.get_for(cpu).lock().remove(&id);
+}
+
+/// Whether any task spawned on the processor numbered `cpu` is still live.
+pub(super) fn any_on(cpu: u32) -> bool {
+    !TASKS.get_for(cpu).lock().is_empty()
+}
+
+/// Returns the live tasks of the processor numbered `cpu`, ordered by id.
+pub fn tasks_on(cpu: u32) -> Vec<Arc<TaskInfo>> {
+    TASKS.get_for(cpu).lock().values().cloned().collect()
+}
@@ -0,0 +1,121 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+
+use super::lock::IrqSpinLock;
+
+/// Why a task did not produce its output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task panicked with this message.
+    Panicked(String),
+    /// The task was dropped before it finished.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "task panicked: {}", message),
+            JoinError::Cancelled => f.write_str("task was cancelled"),
+        }
+    }
+}
+
+struct JoinState<T> {
+    result: IrqSpinLock<Option<Result<T, JoinError>>>,
+    waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    /// Stores the result of the task, unless it already has one.
+    fn complete(&self, result: Result<T, JoinError>) {
+        let mut slot = self.result.lock();
+        if slot.is_none() {
+            *slot = Some(result);
+        }
+        drop(slot);
+        self.waker.wake();
+    }
+}
+
+/// Notified by the executor when a task ends without finishing its future.
+pub(super) trait Abort: Send + Sync {
+    fn abort(&self, error: JoinError);
+}
+
+impl<T: Send> Abort for JoinState<T> {
+    fn abort(&self, error: JoinError) {
+        self.complete(Err(error));
+    }
+}
+
+/// Waits for a task to finish and returns its output.
+///
+/// Dropping the handle detaches the task, it keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns a way to end the task with an error.
+    pub(super) fn abort_handle<'a>(&self) -> Arc<dyn Abort + 'a>
+    where
+        T: Send + 'a,
+    {
+        self.state.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let state = &self.state;
+        state.waker.register(cx.waker());
+        match state.result.lock().take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `future` so its output is sent to the returned handle.
+pub(super) fn joinable<F>(
+    future: F,
+) -> (
+    impl Future<Output = ()>,
+    Arc<dyn Abort>,
+    JoinHandle<F::Output>,
+)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (future, handle) = wrap(future);
+    (future, handle.state.clone(), handle)
+}
+
+/// Like [`joinable`], for futures that may borrow.
+pub(super) fn wrap<'a, F>(future: F) -> (impl Future<Output = ()> + 'a, JoinHandle<F::Output>)
+where
+    F: Future + 'a,
+{
+    let state = Arc::new(JoinState {
+        result: IrqSpinLock::new(None),
+        waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle {
+        state: state.clone(),
+    };
+    let future = async move {
+        let output = future.await;
+        state.complete(Ok(output));
+    };
+    (future, handle)
+}
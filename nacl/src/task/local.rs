@@ -0,0 +1,109 @@
+//! Task-local storage.
+//!
+//! Keys are declared with [`task_local!`]. Every task has its own value for each
+//! key, created on first access in that task. The executor installs the values
+//! of a task for the duration of each of its polls, so they can only be
+//! accessed from code running inside a task.
+//!
+//! Like thread locals, the values can only be borrowed immutably, so values
+//! that change need interior mutability. Spawned tasks start with fresh values,
+//! unless they [inherit](super::Task::inherit) the values of the spawning task.
+//!
+//! ```ignore
+//! task_local! {
+//!     static LOG_CONTEXT: RefCell<String> = RefCell::new(String::new());
+//! }
+//!
+//! LOG_CONTEXT.with(|context| context.borrow_mut().push_str("ahci"));
+//! ```
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::any::Any;
+use core::cell::RefCell;
+
+use super::thread;
+
+/// A key for task-local values of type `T`, see the [module level documentation](self).
+pub struct LocalKey<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> LocalKey<T> {
+        LocalKey { init }
+    }
+
+    /// Every key is a different static, so its address identifies it.
+    fn id(&'static self) -> usize {
+        self as *const LocalKey<T> as usize
+    }
+
+    /// Calls `f` with the value of the running task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from inside a task.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("task local accessed outside of a task")
+    }
+
+    /// Like [`LocalKey::with`], but returns `None` if not called from inside a task.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let locals = thread::try_current()?.locals.get()?;
+        // SAFETY: the executor installs the locals of a task only while it
+        // polls it, and on the thread polling it.
+        let locals = unsafe { locals.as_ref() };
+        let value = locals.get_or_init(self);
+        // SAFETY: values are boxed and only dropped with the task, so the
+        // reference stays valid even if `f` adds other values.
+        Some(f(unsafe { &*value }))
+    }
+}
+
+/// The task-local values of a task.
+#[derive(Default)]
+pub(super) struct Locals {
+    /// boxed values, by the id of their key.
+    values: RefCell<BTreeMap<usize, Box<dyn Any + Send>>>,
+}
+
+impl Locals {
+    fn get_or_init<T: Send + 'static>(&self, key: &'static LocalKey<T>) -> *const T {
+        if let Some(value) = self.values.borrow().get(&key.id()) {
+            return value.downcast_ref::<T>().unwrap();
+        }
+        // not borrowed while initializing, which may access other keys.
+        let value = Box::new((key.init)());
+        let mut values = self.values.borrow_mut();
+        values
+            .entry(key.id())
+            .or_insert(value)
+            .downcast_ref::<T>()
+            .unwrap()
+    }
+
+    pub(super) fn insert<T: Send + 'static>(&mut self, key: &'static LocalKey<T>, value: T) {
+        self.values.get_mut().insert(key.id(), Box::new(value));
+    }
+}
+
+/// Declares task-local keys, see the [module level documentation](self).
+pub macro task_local {
+    () => {},
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr) => {
+        $crate::task::local::task_local!($(#[$attr])* $vis static $name: $ty = $init;);
+    },
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::local::LocalKey<$ty> = {
+            fn init() -> $ty {
+                $init
+            }
+            $crate::task::local::LocalKey::new(init)
+        };
+        $crate::task::local::task_local!($($rest)*);
+    },
+}
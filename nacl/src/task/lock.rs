@@ -1,124 +1,120 @@
 use core::cell::UnsafeCell;
 use core::future::Future;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::task::Poll;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
 
-use futures_util::task::AtomicWaker;
+use nacl_core::sync;
+use x86_64::instructions::interrupts;
 
+#[cfg(feature = "lockdep")]
+use super::lockdep::{self, Acquire};
+
+/// The async mutex of [`nacl_core`], with its synchronous acquisitions checked
+/// by lockdep.
+///
+/// Lockdep tracks the locks each processor holds, but a guard of [`lock`]
+/// may be held across await points while its task moves to other processors,
+/// so asynchronous acquisitions are not tracked. Guards of
+/// [`lock_or_spin`] and [`try_lock`] are, and must not be held across await
+/// points.
+///
+/// [`lock`]: Mutex::lock
+/// [`lock_or_spin`]: Mutex::lock_or_spin
+/// [`try_lock`]: Mutex::try_lock
 pub struct Mutex<T> {
-    inner: UnsafeCell<T>,
-    locked: AtomicBool,
-    waker: AtomicWaker,
+    inner: sync::Mutex<T>,
 }
 
-unsafe impl<T: Sync> Sync for Mutex<T> {}
-unsafe impl<T: Send> Send for Mutex<T> {}
-
 pub struct MutexGuard<'a, T> {
-    inner: &'a mut T,
-    locked: &'a AtomicBool,
-    waker: &'a AtomicWaker,
+    guard: sync::MutexGuard<'a, T>,
+    /// whether lockdep tracks the guard.
+    #[cfg(feature = "lockdep")]
+    tracked: bool,
 }
 
 pub struct MutexLockFuture<'a, T> {
-    inner: &'a UnsafeCell<T>,
-    locked: &'a AtomicBool,
-    waker: &'a AtomicWaker,
-}
-
-/// Check whether the lock is currently locked. Returns `Ok` on success and the lock is locked
-fn check(locked: &AtomicBool) -> Result<bool, bool> {
-    locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+    future: sync::MutexLockFuture<'a, T>,
 }
 
 impl<'a, T> Future for MutexLockFuture<'a, T> {
     type Output = MutexGuard<'a, T>;
 
-    fn poll(
-        self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> Poll<Self::Output> {
-        let Self {
-            locked,
-            inner,
-            waker,
-        } = self.get_mut();
-
-        // Fast path. Avoid registering this task's waker.
-        if check(locked).is_ok() {
-            return Poll::Ready(MutexGuard {
-                waker,
-                locked,
-                inner: unsafe { &mut *inner.get() },
-            });
-        }
-
-        waker.register(cx.waker());
-        if check(locked).is_ok() {
-            Poll::Ready(MutexGuard {
-                waker,
-                locked,
-                inner: unsafe { &mut *inner.get() },
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.future)
+            .poll(cx)
+            .map(|guard| MutexGuard {
+                guard,
+                #[cfg(feature = "lockdep")]
+                tracked: false,
             })
-        } else {
-            Poll::Pending
-        }
     }
 }
 
-static NEW_WAKER: AtomicWaker = AtomicWaker::new();
-
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
-            locked: AtomicBool::new(false),
-            inner: UnsafeCell::new(value),
-            waker: AtomicWaker::new(),
+            inner: sync::Mutex::new(value),
         }
     }
 
     pub fn lock(&self) -> MutexLockFuture<'_, T> {
         MutexLockFuture {
-            locked: &self.locked,
-            inner: &self.inner,
-            waker: &self.waker,
+            future: self.inner.lock(),
         }
     }
 
+    /// Spins until the lock is acquired.
+    ///
+    /// This does not touch the interrupt flag, so it must not be used for data
+    /// shared with interrupt handlers. Use [`IrqSpinLock`] for that.
+    #[track_caller]
     pub fn lock_or_spin(&self) -> MutexGuard<'_, T> {
-        while check(&self.locked).is_err() {}
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.inner, Location::caller(), Acquire::Blocking {
+            irqs_enabled: interrupts::are_enabled(),
+        });
 
         MutexGuard {
-            waker: &NEW_WAKER,
-            locked: &self.locked,
-            inner: unsafe { &mut *self.inner.get() },
+            guard: self.inner.lock_or_spin(),
+            #[cfg(feature = "lockdep")]
+            tracked: true,
         }
     }
 
+    #[track_caller]
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
-        if check(&self.locked).is_ok() {
-            Some(MutexGuard {
-                waker: &NEW_WAKER,
-                locked: &self.locked,
-                inner: unsafe { &mut *self.inner.get() },
-            })
-        } else {
-            None
-        }
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.inner, Location::caller(), Acquire::Try {
+            irqs_enabled: interrupts::are_enabled(),
+        });
+        Some(MutexGuard {
+            guard,
+            #[cfg(feature = "lockdep")]
+            tracked: true,
+        })
     }
 }
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
-        self.locked.store(false, Ordering::Release);
-        self.waker.wake();
+        // released before `guard` unlocks the mutex.
+        #[cfg(feature = "lockdep")]
+        if self.tracked {
+            lockdep::release(sync::MutexGuard::mutex(&self.guard));
+        }
     }
 }
 
 impl<T> DerefMut for MutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.inner
+        &mut self.guard
     }
 }
 
@@ -126,6 +122,139 @@ impl<T> Deref for MutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        self.inner
+        &self.guard
+    }
+}
+
+/// A ticket spinlock that disables interrupts while it is held.
+///
+/// Interrupts are disabled before waiting for the lock and the previous state
+/// is restored when the guard is dropped, so the lock can be shared between
+/// interrupt handlers and normal code. Waiters acquire the lock in the order
+/// they started waiting. In debug builds, locking it again on the processor
+/// that holds it panics instead of deadlocking.
+pub struct IrqSpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    /// number of the holding processor plus one, zero if unknown or unlocked.
+    #[cfg(debug_assertions)]
+    owner: AtomicU32,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    /// whether interrupts were enabled before locking.
+    interrupts_enabled: bool,
+    /// must be released on the processor that acquired it.
+    _notsend: PhantomData<*const ()>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            #[cfg(debug_assertions)]
+            owner: AtomicU32::new(0),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn owner_id() -> u32 {
+        crate::cores::try_id().map_or(0, |id| id + 1)
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self, Location::caller(), Acquire::Blocking {
+            irqs_enabled: false,
+        });
+
+        #[cfg(debug_assertions)]
+        {
+            let me = Self::owner_id();
+            if me != 0 && self.owner.load(Ordering::Relaxed) == me {
+                panic!("recursive locking of IrqSpinLock on CPU {}", me - 1);
+            }
+        }
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+
+        self.guard(interrupts_enabled)
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        if self
+            .next_ticket
+            .compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            #[cfg(feature = "lockdep")]
+            lockdep::acquire(self, Location::caller(), Acquire::Try {
+                irqs_enabled: false,
+            });
+            Some(self.guard(interrupts_enabled))
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    fn guard(&self, interrupts_enabled: bool) -> IrqSpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        self.owner.store(Self::owner_id(), Ordering::Relaxed);
+
+        IrqSpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+            _notsend: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.owner.store(0, Ordering::Relaxed);
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock);
+
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.inner.get() }
     }
 }
@@ -0,0 +1,273 @@
+//! Lock dependency validator, enabled with the `lockdep` feature.
+//!
+//! Every lock is its own class, identified by its address. Each processor keeps
+//! a stack of the locks it holds, and whenever a lock is acquired while others
+//! are held, an edge from each held class to the new one is recorded in a global
+//! graph. An edge that closes a cycle means two call paths take the same locks in
+//! opposite orders and can deadlock. A class that is taken in interrupt context
+//! and also held with interrupts enabled elsewhere can deadlock against itself.
+//!
+//! The tracked locks are [`IrqSpinLock`](super::lock::IrqSpinLock), which is
+//! always held with interrupts disabled, and the synchronous acquisitions of
+//! the async [`Mutex`](super::lock::Mutex), which may be held with interrupts
+//! enabled. Guards the mutex returns asynchronously may be held across await
+//! points and move to other processors with their task, so they are not on the
+//! stack of any processor and are not tracked.
+//!
+//! Nothing is tracked in NMI handlers either. They may have interrupted the
+//! holder of the heap lock, and recording a lock allocates.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::panic::Location;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::cores::{self, percpu};
+use crate::sprintln;
+
+type Class = usize;
+type CallSite = &'static Location<'static>;
+
+/// Maximum number of locks a processor can hold at once while being tracked.
+const MAX_HELD: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: Class,
+    location: CallSite,
+}
+
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+percpu! {
+    static HELD: RefCell<HeldLocks> = RefCell::new(HeldLocks {
+        locks: [None; MAX_HELD],
+        len: 0,
+    });
+    /// set while lockdep itself runs, so its own printing is not tracked.
+    static IN_LOCKDEP: Cell<bool> = Cell::new(false);
+}
+
+#[derive(Default)]
+struct ClassInfo {
+    /// where the class was first acquired, used to name it in reports.
+    first: Option<CallSite>,
+    /// where the class was first acquired in interrupt context.
+    in_irq: Option<CallSite>,
+    /// where the class was first held with interrupts enabled.
+    irqs_enabled: Option<CallSite>,
+    /// whether an irq inconsistency was already reported.
+    irq_reported: bool,
+}
+
+/// `to` was acquired at `acquired` while `from` was held since `held`.
+#[derive(Clone, Copy)]
+struct Dependency {
+    to: Class,
+    held: CallSite,
+    acquired: CallSite,
+}
+
+#[derive(Default)]
+struct Graph {
+    classes: BTreeMap<Class, ClassInfo>,
+    edges: BTreeMap<Class, Vec<Dependency>>,
+}
+
+static GRAPH: spin::Mutex<Option<Graph>> = spin::Mutex::new(None);
+
+/// How a lock is being acquired.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Acquire {
+    /// Waits until the lock is available. `irqs_enabled` tells whether
+    /// interrupts stay enabled while the lock is held.
+    Blocking { irqs_enabled: bool },
+    /// Already acquired without waiting, so it cannot deadlock by itself.
+    Try { irqs_enabled: bool },
+}
+
+impl Graph {
+    /// Returns a path of dependencies from `from` to `to`, if there is one.
+    fn path(&self, from: Class, to: Class) -> Option<Vec<(Class, Dependency)>> {
+        let mut visited = BTreeSet::new();
+        let mut path = Vec::new();
+        self.dfs(from, to, &mut visited, &mut path).then_some(path)
+    }
+
+    fn dfs(
+        &self,
+        node: Class,
+        to: Class,
+        visited: &mut BTreeSet<Class>,
+        path: &mut Vec<(Class, Dependency)>,
+    ) -> bool {
+        if node == to {
+            return true;
+        }
+        if !visited.insert(node) {
+            return false;
+        }
+        for dep in self.edges.get(&node).into_iter().flatten() {
+            path.push((node, *dep));
+            if self.dfs(dep.to, to, visited, path) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+
+    fn name(&self, class: Class) -> ClassName {
+        ClassName {
+            class,
+            first: self.classes.get(&class).and_then(|info| info.first),
+        }
+    }
+
+    fn add_dependency(&mut self, from: Held, to: Class, acquired: CallSite) {
+        let edges = self.edges.entry(from.class).or_default();
+        if edges.iter().any(|dep| dep.to == to) {
+            return;
+        }
+
+        // adding `from -> to` closes a cycle if `to` already leads to `from`.
+        if let Some(path) = self.path(to, from.class) {
+            sprintln!("lockdep: possible circular locking dependency detected");
+            sprintln!(
+                "  acquiring {} at {}\n  while holding {} acquired at {}",
+                self.name(to),
+                acquired,
+                self.name(from.class),
+                from.location
+            );
+            sprintln!("  existing dependency chain:");
+            for (class, dep) in path {
+                sprintln!(
+                    "    {} acquired at {} while holding {} acquired at {}",
+                    self.name(dep.to),
+                    dep.acquired,
+                    self.name(class),
+                    dep.held
+                );
+            }
+        }
+
+        self.edges.entry(from.class).or_default().push(Dependency {
+            to,
+            held: from.location,
+            acquired,
+        });
+    }
+
+    fn record_usage(&mut self, class: Class, location: CallSite, in_irq: bool, irqs_enabled: bool) {
+        let info = self.classes.entry(class).or_default();
+        info.first.get_or_insert(location);
+        if in_irq {
+            info.in_irq.get_or_insert(location);
+        } else if irqs_enabled {
+            info.irqs_enabled.get_or_insert(location);
+        }
+
+        if let (Some(in_irq), Some(irqs_enabled), false) =
+            (info.in_irq, info.irqs_enabled, info.irq_reported)
+        {
+            info.irq_reported = true;
+            sprintln!("lockdep: inconsistent interrupt usage of {}", self.name(class));
+            sprintln!("  acquired in interrupt context at {}", in_irq);
+            sprintln!("  held with interrupts enabled at {}", irqs_enabled);
+        }
+    }
+}
+
+struct ClassName {
+    class: Class,
+    first: Option<CallSite>,
+}
+
+impl core::fmt::Display for ClassName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "lock {:#x}", self.class)?;
+        if let Some(first) = self.first {
+            write!(f, " (first locked at {})", first)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `f` unless lockdep is already running on this processor, it runs an
+/// NMI handler, or per-CPU data is not available yet.
+fn enter(f: impl FnOnce()) {
+    if cores::try_id().is_none() || cores::in_nmi() {
+        return;
+    }
+    without_interrupts(|| {
+        let in_lockdep = IN_LOCKDEP.get();
+        if in_lockdep.replace(true) {
+            return;
+        }
+        f();
+        in_lockdep.set(false);
+    })
+}
+
+/// Records that the lock at `lock` is being acquired at `location`.
+pub fn acquire<L: ?Sized>(lock: &L, location: CallSite, mode: Acquire) {
+    let class = lock as *const L as *const () as Class;
+    enter(|| {
+        let mut held = HELD.get().borrow_mut();
+        let mut graph = GRAPH.lock();
+        let graph = graph.get_or_insert_with(Graph::default);
+
+        let (irqs_enabled, blocking) = match mode {
+            Acquire::Blocking { irqs_enabled } => (irqs_enabled, true),
+            Acquire::Try { irqs_enabled } => (irqs_enabled, false),
+        };
+        graph.record_usage(class, location, cores::in_irq(), irqs_enabled);
+
+        let len = held.len;
+        for prev in held.locks[..len].iter().flatten().copied() {
+            if prev.class == class {
+                if blocking {
+                    sprintln!(
+                        "lockdep: recursive locking of {} at {}, already held since {}",
+                        graph.name(class),
+                        location,
+                        prev.location
+                    );
+                }
+            } else if blocking {
+                graph.add_dependency(prev, class, location);
+            }
+        }
+
+        if len == MAX_HELD {
+            sprintln!("lockdep: too many locks held on CPU {}", cores::id());
+            return;
+        }
+        held.locks[len] = Some(Held { class, location });
+        held.len += 1;
+    })
+}
+
+/// Records that the lock at `lock` was released.
+pub fn release<L: ?Sized>(lock: &L) {
+    let class = lock as *const L as *const () as Class;
+    enter(|| {
+        let mut held = HELD.get().borrow_mut();
+        let len = held.len;
+        // locks are not necessarily released in reverse order.
+        if let Some(index) = held.locks[..len]
+            .iter()
+            .rposition(|held| held.is_some_and(|held| held.class == class))
+        {
+            held.locks[index..len].rotate_left(1);
+            held.locks[len - 1] = None;
+            held.len -= 1;
+        }
+    })
+}
@@ -0,0 +1,361 @@
+//! A lock-free concurrent hash map.
+//!
+//! Every bucket is a lock-free linked list sorted by hash (Michael, "High
+//! Performance Dynamic Lock-Free Hash Tables and List-Based Sets", 2002).
+//! Removing an entry first marks the `next` pointer of its node, so that no node
+//! is ever linked behind a node that is being removed, and then unlinks it. The
+//! nodes are freed through the [epoch collector](super::gc), once no reader can
+//! still see them.
+//!
+//! The number of buckets is fixed when the map is created, so it should be
+//! chosen for the number of entries expected.
+//!
+//! Operations pin the processor with [`rcu_read`], which keeps interrupts
+//! disabled meanwhile, so they can be used from any processor or thread.
+//! Interrupt handlers may look entries up, but not insert or remove them: that
+//! allocates, and the heap lock does not disable interrupts.
+
+use alloc::boxed::Box;
+use core::borrow::Borrow;
+use core::hash::{BuildHasher, Hash};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_epoch::{unprotected, Atomic, Guard, Owned, Shared};
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use super::rcu::rcu_read;
+
+/// Number of buckets of [`ConcurrentMap::new`].
+const DEFAULT_BUCKETS: usize = 64;
+
+/// Tag of the `next` pointer of a node that is being removed.
+const REMOVED: usize = 1;
+
+struct Node<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+    next: Atomic<Node<K, V>>,
+}
+
+pub struct ConcurrentMap<K, V, S = DefaultHashBuilder> {
+    buckets: Box<[Atomic<Node<K, V>>]>,
+    hasher: S,
+    len: AtomicUsize,
+}
+
+/// Where an entry is, or would be inserted.
+struct Position<'g, K, V> {
+    found: bool,
+    /// the pointer to `current`.
+    previous: &'g Atomic<Node<K, V>>,
+    current: Shared<'g, Node<K, V>>,
+}
+
+impl<K, V> ConcurrentMap<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+{
+    pub fn new() -> Self {
+        Self::with_buckets(DEFAULT_BUCKETS)
+    }
+
+    /// Creates a map with `buckets` buckets, rounded up to a power of two.
+    pub fn with_buckets(buckets: usize) -> Self {
+        Self::with_buckets_and_hasher(buckets, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for ConcurrentMap<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> ConcurrentMap<K, V, S>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher,
+{
+    pub fn with_buckets_and_hasher(buckets: usize, hasher: S) -> Self {
+        let buckets = buckets.max(1).next_power_of_two();
+        ConcurrentMap {
+            buckets: (0..buckets).map(|_| Atomic::null()).collect(),
+            hasher,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of entries. Only exact if the map is not modified.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bucket(&self, hash: u64) -> &Atomic<Node<K, V>> {
+        &self.buckets[hash as usize & (self.buckets.len() - 1)]
+    }
+
+    /// Finds `key` in the bucket of `hash`, unlinking the removed nodes on the way.
+    fn find<'g, Q>(&'g self, hash: u64, key: &Q, guard: &'g Guard) -> Position<'g, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        'retry: loop {
+            let mut previous = self.bucket(hash);
+            let mut current = previous.load(Ordering::Acquire, guard);
+            loop {
+                // SAFETY: nodes are only freed once no guard can see them.
+                let Some(node) = (unsafe { current.as_ref() }) else {
+                    break 'retry Position {
+                        found: false,
+                        previous,
+                        current,
+                    };
+                };
+                let next = node.next.load(Ordering::Acquire, guard);
+                if next.tag() == REMOVED {
+                    let next = next.with_tag(0);
+                    match previous.compare_exchange(
+                        current,
+                        next,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                        guard,
+                    ) {
+                        // SAFETY: the node is unlinked, and only the thread
+                        // that unlinked it frees it.
+                        Ok(_) => unsafe { guard.defer_destroy(current) },
+                        // the previous node changed or is being removed too.
+                        Err(_) => continue 'retry,
+                    }
+                    current = next;
+                    continue;
+                }
+
+                if node.hash > hash || (node.hash == hash && node.key.borrow() == key) {
+                    break 'retry Position {
+                        found: node.hash == hash,
+                        previous,
+                        current,
+                    };
+                }
+                previous = &node.next;
+                current = next;
+            }
+        }
+    }
+
+    /// Returns the value of `key`. It stays valid while `guard` is alive.
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let position = self.find(hash, key, guard);
+        if position.found {
+            // SAFETY: `find` only returns found nodes that are not null.
+            Some(unsafe { &position.current.deref().value })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a clone of the value of `key`.
+    pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        rcu_read(|guard| self.get(key, guard).cloned())
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        rcu_read(|guard| self.get(key, guard).is_some())
+    }
+
+    /// Inserts `value` for `key`. Returns the value back if `key` already has one.
+    pub fn insert(&self, key: K, value: V) -> Result<(), V> {
+        let hash = self.hasher.hash_one(&key);
+        let mut node = Owned::new(Node {
+            hash,
+            key,
+            value,
+            next: Atomic::null(),
+        });
+        rcu_read(|guard| loop {
+            let position = self.find(hash, &node.key, guard);
+            if position.found {
+                return Err(node.into_box().value);
+            }
+            node.next.store(position.current, Ordering::Relaxed);
+            match position.previous.compare_exchange(
+                position.current,
+                node,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            ) {
+                Ok(_) => {
+                    self.len.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(error) => node = error.new,
+            }
+        })
+    }
+
+    /// Removes `key`, returning whether it was in the map. The value is dropped
+    /// once no reader can see it anymore.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        rcu_read(|guard| loop {
+            let position = self.find(hash, key, guard);
+            if !position.found {
+                return false;
+            }
+            // SAFETY: `find` only returns found nodes that are not null.
+            let node = unsafe { position.current.deref() };
+            let next = node.next.load(Ordering::Acquire, guard);
+            if next.tag() == REMOVED {
+                // removed concurrently, `find` unlinks it.
+                continue;
+            }
+            if node
+                .next
+                .compare_exchange(
+                    next,
+                    next.with_tag(REMOVED),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    guard,
+                )
+                .is_err()
+            {
+                continue;
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+
+            // the node is removed now, try to unlink it right away.
+            match position.previous.compare_exchange(
+                position.current,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            ) {
+                // SAFETY: the node is unlinked, and only the thread that
+                // unlinked it frees it.
+                Ok(_) => unsafe { guard.defer_destroy(position.current) },
+                Err(_) => {
+                    self.find(hash, key, guard);
+                }
+            }
+            return true;
+        })
+    }
+
+    /// Calls `f` with every entry. Entries inserted or removed concurrently may
+    /// or may not be visited.
+    ///
+    /// Interrupts are disabled while iterating, so `f` should be quick.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        rcu_read(|guard| {
+            for bucket in self.buckets.iter() {
+                let mut current = bucket.load(Ordering::Acquire, guard);
+                // SAFETY: nodes are only freed once no guard can see them.
+                while let Some(node) = unsafe { current.as_ref() } {
+                    let next = node.next.load(Ordering::Acquire, guard);
+                    if next.tag() != REMOVED {
+                        f(&node.key, &node.value);
+                    }
+                    current = next.with_tag(0);
+                }
+            }
+        })
+    }
+}
+
+impl<K, V, S> Drop for ConcurrentMap<K, V, S> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` means no one else can access the map. Removed
+        // nodes that are still linked are freed here, not by the collector.
+        unsafe {
+            let guard = unprotected();
+            for bucket in self.buckets.iter() {
+                let mut current = bucket.load(Ordering::Relaxed, guard);
+                while !current.is_null() {
+                    let next = current.deref().next.load(Ordering::Relaxed, guard);
+                    drop(current.into_owned());
+                    current = next.with_tag(0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConcurrentMap;
+    use crate::testing::kernel_test;
+
+    #[kernel_test]
+    fn insert() {
+        let map = ConcurrentMap::new();
+        assert_eq!(map.insert(1, "one"), Ok(()));
+        assert_eq!(map.insert(1, "uno"), Err("uno"));
+        assert_eq!(map.get_cloned(&1), Some("one"));
+        assert_eq!(map.get_cloned(&2), None);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[kernel_test]
+    fn remove() {
+        let map = ConcurrentMap::new();
+        for i in 0..100 {
+            map.insert(i, i * 2).unwrap();
+        }
+        for i in (0..100).step_by(2) {
+            assert!(map.remove(&i));
+        }
+        assert!(!map.remove(&0));
+        assert_eq!(map.len(), 50);
+        for i in 0..100 {
+            assert_eq!(map.contains_key(&i), i % 2 == 1);
+        }
+    }
+
+    #[kernel_test]
+    fn shared_bucket() {
+        // every entry lands in the same list.
+        let map = ConcurrentMap::with_buckets(1);
+        for i in 0..32 {
+            map.insert(i, i).unwrap();
+        }
+        assert!(map.remove(&16));
+        let mut sum = 0;
+        map.for_each(|_, &value| sum += value);
+        assert_eq!(sum, (0..32).sum::<i32>() - 16);
+    }
+}
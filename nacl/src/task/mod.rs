@@ -1,35 +1,42 @@
+pub mod cancel;
+pub mod combinator;
 pub mod crossbeam;
 pub mod executor;
 pub mod gc;
+pub mod info;
+pub mod join;
+pub mod local;
 pub mod lock;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod map;
+pub mod panic;
+pub mod priority;
+pub mod rcu;
+pub mod scope;
+pub mod thread;
 
-use core::iter;
-
-use crossbeam::{Injector, Stealer, Worker};
-
-pub fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
-    // Pop a task from the local queue, if not empty.
-    local.pop().or_else(|| {
-        // Otherwise, we need to look for a task elsewhere.
-        iter::repeat_with(|| {
-            // Try stealing a batch of tasks from the global queue.
-            global
-                .steal_batch_and_pop(local)
-                // Or try stealing a task from one of the other threads.
-                .or_else(|| stealers.iter().map(|s| s.steal()).collect())
-        })
-        // Loop while no task was stolen and any steal operation needs to be retried.
-        .find(|s| !s.is_retry())
-        // Extract the stolen task, if there is one.
-        .and_then(|s| s.success())
-    })
-}
+use priority::Priority;
+
+use crate::cores::CpuSet;
 
+use alloc::borrow::Cow;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::fmt;
 use core::future::Future;
-use core::pin::Pin;
-use core::sync::atomic::{AtomicU64, Ordering};
-use core::task::{Context, Poll};
+use core::mem;
+use core::panic::Location;
+use core::pin::{pin, Pin};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use info::TaskInfo;
+use join::{Abort, JoinError, JoinHandle};
+use local::{LocalKey, Locals};
+use thread::Thread;
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(u64);
@@ -41,21 +48,146 @@ impl TaskId {
     }
 }
 
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
 pub struct Task {
-    id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    info: Arc<TaskInfo>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// notifies the `JoinHandle` if the task ends without finishing.
+    abort: Option<Arc<dyn Abort>>,
+    locals: Locals,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    #[track_caller]
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
-            id: TaskId::new(),
+            info: Arc::new(TaskInfo::new(Location::caller())),
             future: Box::pin(future),
+            abort: None,
+            locals: Locals::default(),
         }
     }
 
-    fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+    /// Like [`Task::new`], but also returns a handle to wait for the output of
+    /// `future`, or the panic that ended it.
+    #[track_caller]
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, abort, handle) = join::joinable(future);
+        let mut task = Task::new(future);
+        task.abort = Some(abort);
+        (task, handle)
+    }
+
+    /// Only called before spawning, when nothing else refers to the info.
+    fn info_mut(&mut self) -> &mut TaskInfo {
+        Arc::get_mut(&mut self.info).expect("task info is shared before spawning")
+    }
+
+    /// Sets the name shown when listing tasks.
+    pub fn with_name(mut self, name: impl Into<Cow<'static, str>>) -> Task {
+        self.info_mut().name = name.into();
+        self
+    }
+
+    /// Sets the priority this task is scheduled with.
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.info_mut().priority = priority;
+        self
+    }
+
+    /// Sets the value of `key` for this task.
+    pub fn with_local<T: Send + 'static>(mut self, key: &'static LocalKey<T>, value: T) -> Task {
+        self.locals.insert(key, value);
+        self
+    }
+
+    /// Gives this task a copy of the value of `key` in the running task.
+    ///
+    /// Does nothing if not called from inside a task.
+    pub fn inherit<T: Clone + Send + 'static>(self, key: &'static LocalKey<T>) -> Task {
+        match key.try_with(T::clone) {
+            Some(value) => self.with_local(key, value),
+            None => self,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> TaskId {
+        self.info.id
+    }
+
+    #[inline]
+    pub fn info(&self) -> &Arc<TaskInfo> {
+        &self.info
+    }
+
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        self.info.priority
+    }
+
+    /// Restricts the processors this task may run on.
+    ///
+    /// The task is placed on one processor of `affinity` when spawned and then
+    /// stays there, unless `affinity` contains all processors.
+    pub fn with_affinity(mut self, affinity: CpuSet) -> Task {
+        assert!(affinity != CpuSet::empty(), "task affinity must not be empty");
+        self.info_mut().affinity = affinity;
+        self
+    }
+
+    #[inline]
+    pub fn affinity(&self) -> CpuSet {
+        self.info.affinity
+    }
+
+    /// Whether the task must not be stolen by other processors.
+    #[inline]
+    pub fn is_pinned(&self) -> bool {
+        !self.info.affinity.is_all()
+    }
+
+    /// Tears down the task without finishing it.
+    fn abort(self, error: JoinError) {
+        if let Some(abort) = &self.abort {
+            abort.abort(error);
+        }
+    }
+
+    /// Tears down a task whose poll panicked. The destructors of the frames the
+    /// panic skipped never ran, so the future may be in any state, and dropping
+    /// it could free something twice. It is leaked instead.
+    fn abandon(self, error: JoinError) {
+        let Task { future, abort, .. } = self;
+        mem::forget(future);
+        if let Some(abort) = abort {
+            abort.abort(error);
+        }
+    }
+
+    /// Polls the task on `thread`, with its task locals installed.
+    fn poll(&mut self, context: &mut Context, thread: &Thread) -> Poll<()> {
+        let Task {
+            info,
+            future,
+            locals,
+            ..
+        } = self;
+        thread.locals.set(Some(NonNull::from(&*locals)));
+        let start = crate::arch::tsc();
+        let poll = future.as_mut().poll(context);
+        info.record_poll(crate::arch::tsc() - start);
+        thread.locals.set(None);
+        poll
     }
 }
 
@@ -76,15 +208,86 @@ impl Future for Yield {
     }
 }
 
+/// Spawns `future` as a task on this processor, and returns a handle to wait
+/// for its output. Other processors may steal the task once it runs.
+///
+/// This does not borrow the executor, so it can be called from within a task.
+#[track_caller]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, handle) = Task::joinable(future);
+    executor::spawn_task(task);
+    handle
+}
+
 /// Call in an async function to yield execution back to the executor.
 pub macro ayield() {{
     <$crate::task::Yield as ::core::default::Default>::default().await;
 }}
 
-/*
-pub fn block_on<F: Future>(mut fut: F) -> F::Output {
-    let pin = unsafe { Pin::new_unchecked(&mut fut) };
+/// Runs `future` to completion on this processor, halting between wakes.
+///
+/// This is how code outside of tasks, like the boot code, uses async APIs. If
+/// interrupts are disabled the processor cannot halt, so it spins instead.
+///
+/// # Panics
+///
+/// Panics if called from inside a task, which would block the executor
+/// running it. Await the future instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+    if thread::try_current().is_some_and(|me| me.locals.get().is_some()) {
+        panic!("block_on called from inside a task, use .await instead");
+    }
+
+    let wake = Arc::new(BlockOnWake {
+        woken: AtomicBool::new(true),
+        cpu: crate::cores::try_id(),
+    });
+    let waker = Waker::from(wake.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    let can_halt = interrupts::are_enabled();
     loop {
-        pin.poll(cx)
+        if wake.woken.swap(false, Ordering::Acquire) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        } else if can_halt {
+            // a wake from an interrupt between the check and `hlt` would be missed.
+            interrupts::disable();
+            if wake.woken.load(Ordering::Acquire) {
+                interrupts::enable();
+            } else {
+                enable_and_hlt();
+            }
+        } else {
+            core::hint::spin_loop();
+        }
     }
-}*/
+}
+
+struct BlockOnWake {
+    woken: AtomicBool,
+    /// the processor halting in `block_on`.
+    cpu: Option<u32>,
+}
+
+impl Wake for BlockOnWake {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        if let Some(cpu) = self.cpu {
+            if crate::cores::try_id() != Some(cpu) {
+                crate::arch::ipi::reschedule(cpu);
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Isolating panics.
+//!
+//! The kernel is built with `panic = "abort"`, so panics cannot unwind. Instead,
+//! [`catch`] sets a catch point on the running thread, and the panic handler
+//! jumps back to it with [`throw`]. The executor polls every task inside
+//! [`catch`], so a panicking task is torn down while the rest of the kernel
+//! keeps running. Exceptions caused by the running code, like page faults, are
+//! reported as panics, so they are isolated the same way.
+//!
+//! Destructors of the frames that are skipped do not run. Locks those frames
+//! held stay locked, which is why panics in interrupt handlers are never
+//! caught. Use [`PanicMode::Halt`] to stop the machine on the first panic instead.
+
+use alloc::string::{String, ToString};
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::thread;
+use crate::arch::context::{self, CatchPoint};
+use crate::cores;
+
+/// What happens when code running inside [`catch`] panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicMode {
+    /// Abandon the code inside [`catch`], and continue after it.
+    Isolate,
+    /// Halt the processor, keeping its state around for debugging.
+    Halt,
+}
+
+static HALT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_mode(mode: PanicMode) {
+    HALT.store(mode == PanicMode::Halt, Ordering::Relaxed);
+}
+
+pub fn mode() -> PanicMode {
+    if HALT.load(Ordering::Relaxed) {
+        PanicMode::Halt
+    } else {
+        PanicMode::Isolate
+    }
+}
+
+pub(super) struct Catch {
+    point: CatchPoint,
+    message: Option<String>,
+}
+
+/// Runs `f`, returning the panic message if it panicked.
+///
+/// Before threads are initialized on this processor, panics are not caught.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    let Some(me) = thread::try_current() else {
+        return Ok(f());
+    };
+
+    let mut catch = Catch {
+        point: CatchPoint::new(),
+        message: None,
+    };
+    let catch = NonNull::from(&mut catch);
+    let outer = me.catch.replace(Some(catch));
+
+    let mut result = None;
+    // SAFETY: `catch` lives until the end of this function.
+    let point = unsafe { addr_of_mut!((*catch.as_ptr()).point) };
+    let completed = unsafe { context::call_with_catch(point, || result = Some(f())) };
+    me.catch.set(outer);
+
+    if completed {
+        Ok(result.unwrap())
+    } else {
+        // SAFETY: `throw` is done with `catch` by now.
+        Err(unsafe { (*catch.as_ptr()).message.take() }.unwrap_or_default())
+    }
+}
+
+/// Called by the panic handler. Jumps to the innermost catch point of the
+/// running thread, or returns if the panic cannot be caught.
+pub fn throw(info: &PanicInfo) {
+    if mode() == PanicMode::Halt || cores::try_id().is_none() || cores::in_irq() {
+        return;
+    }
+    let Some(me) = thread::try_current() else {
+        return;
+    };
+    // taken, so a second panic before `catch` returns is not caught.
+    let Some(catch) = me.catch.take() else {
+        return;
+    };
+    drop(me);
+
+    let catch = catch.as_ptr();
+    // SAFETY: the catch point belongs to a `catch` call of this thread that is
+    // still running further up the stack.
+    unsafe {
+        (*catch).message = Some(info.message().to_string());
+        context::throw(addr_of!((*catch).point))
+    }
+}
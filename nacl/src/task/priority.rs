@@ -0,0 +1,27 @@
+//! Task priorities, and the ready queues of [`nacl_core::executor`] that
+//! schedule by them.
+
+use core::num::Wrapping;
+
+pub use nacl_core::executor::Priority;
+use nacl_core::executor::TimerSource;
+
+use super::crossbeam::KernelEpoch;
+use super::TaskId;
+use crate::cores::cpu;
+
+/// The timer ticks of the current processor.
+pub struct Ticks;
+
+impl TimerSource for Ticks {
+    #[inline]
+    fn now() -> Wrapping<usize> {
+        cpu().timer.get()
+    }
+}
+
+/// The ready queues of one processor.
+pub type RunQueues = nacl_core::executor::RunQueues<TaskId, KernelEpoch, Ticks>;
+
+/// Steals tasks from the [`RunQueues`] of another processor.
+pub type RunQueueStealers = nacl_core::executor::RunQueueStealers<TaskId, KernelEpoch, Ticks>;
@@ -0,0 +1,164 @@
+//! Read-copy-update on top of the epoch based [garbage collector](super::gc).
+//!
+//! Readers enter a read-side critical section with [`rcu_read`], which pins the
+//! processor in the current epoch and never blocks. Writers publish a new
+//! version of the data and [defer](rcu_defer) freeing the old one until every
+//! processor that could still see it left its critical section. [`RcuCell`]
+//! packages this for a single value, such as a table of IRQ handlers or a list
+//! of devices that is read often and rarely changed.
+//!
+//! The collector keeps one participant per processor, which is not safe to use
+//! from two threads, or a thread and an interrupt handler, at the same time. So
+//! [pinning](gc::pin) disables interrupts, and critical sections should be short.
+//!
+//! Deferred functions run when the epoch advances, which is checked whenever a
+//! processor pins, and every time the executor reports a [quiescent] state.
+
+use core::sync::atomic::Ordering;
+
+use crossbeam_epoch::{unprotected, Atomic, Guard, Owned};
+
+use super::gc;
+
+/// Runs `f` in a read-side critical section. Pointers loaded with the guard
+/// stay valid until it returns.
+#[inline]
+pub fn rcu_read<R>(f: impl FnOnce(&Guard) -> R) -> R {
+    f(&gc::pin())
+}
+
+/// Calls `f` once no critical section that is currently running can still use
+/// what it frees.
+pub fn rcu_defer(f: impl FnOnce() + Send + 'static) {
+    rcu_read(|guard| guard.defer(f));
+}
+
+/// Reports that this processor is not in a critical section, which lets the
+/// epoch advance and runs the deferred functions that became safe to run.
+///
+/// Called by the executor between polls.
+pub fn quiescent() {
+    rcu_read(|guard| guard.flush());
+}
+
+/// A value that is read without locking and replaced as a whole.
+pub struct RcuCell<T> {
+    value: Atomic<T>,
+}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        RcuCell {
+            value: Atomic::new(value),
+        }
+    }
+
+    /// Calls `f` with the current value.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        rcu_read(|guard| {
+            let value = self.value.load(Ordering::Acquire, guard);
+            // SAFETY: the value is never null, and old values are only freed
+            // after the critical section.
+            f(unsafe { value.deref() })
+        })
+    }
+
+    /// Replaces the value. The old one is dropped once no reader uses it.
+    pub fn replace(&self, value: T) {
+        rcu_read(|guard| {
+            let old = self.value.swap(Owned::new(value), Ordering::AcqRel, guard);
+            // SAFETY: `old` is no longer reachable through the cell.
+            unsafe { guard.defer_destroy(old) };
+        });
+    }
+
+    /// Replaces the value with `f` applied to it. `f` runs again if another
+    /// update happened in the meantime.
+    ///
+    /// `f` runs inside a critical section, so it should be quick.
+    pub fn update(&self, mut f: impl FnMut(&T) -> T) {
+        rcu_read(|guard| {
+            let mut current = self.value.load(Ordering::Acquire, guard);
+            let mut new = Owned::new(f(unsafe { current.deref() }));
+            loop {
+                match self.value.compare_exchange(
+                    current,
+                    new,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    guard,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: `current` is no longer reachable through the cell.
+                        unsafe { guard.defer_destroy(current) };
+                        return;
+                    }
+                    Err(error) => {
+                        current = error.current;
+                        // reuse the allocation for the next attempt.
+                        let mut retry = error.new;
+                        *retry = f(unsafe { current.deref() });
+                        new = retry;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` means no one else can access the value, and
+        // older values are kept alive by their deferred frees.
+        let value = self.value.load(Ordering::Relaxed, unsafe { unprotected() });
+        drop(unsafe { value.into_owned() });
+    }
+}
+
+impl<T: Default + Send + Sync + 'static> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use x86_64::instructions::interrupts;
+
+    use super::{quiescent, rcu_defer, rcu_read, RcuCell};
+    use crate::testing::kernel_test;
+
+    #[kernel_test]
+    fn read_disables_interrupts() {
+        let enabled = interrupts::are_enabled();
+        rcu_read(|_| assert!(!interrupts::are_enabled()));
+        assert_eq!(interrupts::are_enabled(), enabled);
+    }
+
+    #[kernel_test]
+    fn cell() {
+        let cell = RcuCell::new(1);
+        assert_eq!(cell.read(|&value| value), 1);
+        cell.replace(2);
+        cell.update(|&value| value * 10);
+        assert_eq!(cell.read(|&value| value), 20);
+    }
+
+    #[kernel_test]
+    fn deferred_runs() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        rcu_defer(move || flag.store(true, Ordering::Relaxed));
+        // the epoch advances once per quiescent state at most.
+        for _ in 0..100 {
+            quiescent();
+            if ran.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+        panic!("deferred function did not run");
+    }
+}
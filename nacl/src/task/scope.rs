@@ -0,0 +1,188 @@
+//! Structured concurrency.
+//!
+//! A [`Scope`] runs child futures concurrently with the code that spawned them,
+//! as part of the same task. Because the children are owned by the scope, they
+//! may borrow anything that outlives it, and none of them is still running
+//! once [`Scope::run`] finishes or is dropped: they either finished, or were
+//! cancelled by dropping them.
+//!
+//! ```ignore
+//! let devices = pci::devices();
+//! let scope = Scope::new();
+//! for device in &devices {
+//!     scope.spawn(probe(device));
+//! }
+//! scope.join().await;
+//! ```
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::{ready, Future, Ready};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use futures_util::task::AtomicWaker;
+
+use super::combinator::MaybeDone;
+use super::join::{self, Abort, JoinError, JoinHandle};
+use super::lock::IrqSpinLock;
+
+/// Children spawned by one task, see the [module level documentation](self).
+pub struct Scope<'a> {
+    /// children not yet picked up by [`Scope::run`].
+    spawned: IrqSpinLock<Vec<Child<'a>>>,
+    /// the waker of the task running the scope.
+    parent: Arc<AtomicWaker>,
+}
+
+struct Child<'a> {
+    future: Pin<Box<dyn Future<Output = ()> + Send + 'a>>,
+    wake: Arc<ChildWake>,
+    waker: Waker,
+    abort: Arc<dyn Abort + 'a>,
+}
+
+impl Drop for Child<'_> {
+    fn drop(&mut self) {
+        // does nothing if the child finished.
+        self.abort.abort(JoinError::Cancelled);
+    }
+}
+
+struct ChildWake {
+    woken: AtomicBool,
+    parent: Arc<AtomicWaker>,
+}
+
+impl Wake for ChildWake {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.parent.wake();
+    }
+}
+
+impl Default for Scope<'_> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Scope<'a> {
+    pub fn new() -> Self {
+        Scope {
+            spawned: IrqSpinLock::new(Vec::new()),
+            parent: Arc::new(AtomicWaker::new()),
+        }
+    }
+
+    /// Adds `future` to the scope. It starts running once the scope runs.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'a,
+        F::Output: Send + 'a,
+    {
+        let (future, handle) = join::wrap(future);
+        let wake = Arc::new(ChildWake {
+            woken: AtomicBool::new(true),
+            parent: self.parent.clone(),
+        });
+        self.spawned.lock().push(Child {
+            future: Box::pin(future),
+            waker: Waker::from(wake.clone()),
+            wake,
+            abort: handle.abort_handle(),
+        });
+        self.parent.wake();
+        handle
+    }
+
+    /// Runs `body` and the children of the scope, until all of them finished.
+    /// `body` may spawn more children.
+    ///
+    /// Dropping the returned future cancels the children that did not finish.
+    pub fn run<B: Future>(&self, body: B) -> Run<'_, 'a, B> {
+        Run {
+            scope: self,
+            body: MaybeDone::new(body),
+            children: Vec::new(),
+        }
+    }
+
+    /// Runs the children of the scope until all of them finished.
+    pub fn join(&self) -> Run<'_, 'a, Ready<()>> {
+        self.run(ready(()))
+    }
+}
+
+/// Future returned by [`Scope::run`].
+pub struct Run<'s, 'a, B: Future> {
+    scope: &'s Scope<'a>,
+    body: MaybeDone<B>,
+    /// children that were picked up from the scope.
+    children: Vec<Child<'a>>,
+}
+
+impl<B: Future> Future for Run<'_, '_, B> {
+    type Output = B::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<B::Output> {
+        // SAFETY: only `body` is pinned, and it is never moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut body = unsafe { Pin::new_unchecked(&mut this.body) };
+        this.scope.parent.register(cx.waker());
+
+        let body_done = body.as_mut().poll(cx).is_ready();
+        // children may spawn children too, which are polled right away.
+        loop {
+            this.children.append(&mut this.scope.spawned.lock());
+            // only children that were woken since their last poll are polled again.
+            this.children.retain_mut(|child| {
+                if !child.wake.woken.swap(false, Ordering::Acquire) {
+                    return true;
+                }
+                let mut context = Context::from_waker(&child.waker);
+                child.future.as_mut().poll(&mut context).is_pending()
+            });
+            if this.scope.spawned.lock().is_empty() {
+                break;
+            }
+        }
+
+        if body_done && this.children.is_empty() {
+            Poll::Ready(body.take_output().unwrap())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::Scope;
+    use crate::testing::kernel_test;
+
+    #[kernel_test]
+    async fn child_spawns_child() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+        // children can only spawn on a scope that outlives them.
+        let scope: &'static Scope<'static> = Box::leak(Box::new(Scope::new()));
+        scope.spawn(async move {
+            scope.spawn(async {
+                RAN.store(true, Ordering::Relaxed);
+            });
+        });
+        scope.join().await;
+        assert!(RAN.load(Ordering::Relaxed));
+    }
+}
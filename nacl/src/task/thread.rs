@@ -0,0 +1,407 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack and runs until it blocks, yields or uses up
+//! its time slice, which is counted in timer interrupts. A thread stays on the
+//! processor it was spawned on, so per-CPU data remains valid for it.
+//!
+//! [`init`] adopts the code that is already running as the first thread of the
+//! processor, which then runs the async [`Executor`](super::executor::Executor).
+//! A long running task only blocks the executor thread, other threads still run.
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts::{self, enable_and_hlt, without_interrupts};
+use x86_64::VirtAddr;
+
+use super::lock::IrqSpinLock;
+use crate::arch::context::{self, Context};
+use crate::arch::ipi;
+use crate::cores::{self, percpu};
+use crate::emergency_sprintln;
+
+/// Size of the stack of every spawned thread.
+const STACK_SIZE: usize = 32 * 1024;
+
+/// Number of timer interrupts a thread may run before another ready thread is
+/// switched to.
+const TIME_SLICE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    /// Waiting in the run queue.
+    Ready,
+    Running,
+    /// Parked until [`Thread::unpark`] is called.
+    Blocked,
+    Exited,
+}
+
+impl State {
+    fn from_u8(state: u8) -> State {
+        match state {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+}
+
+struct Stack {
+    bottom: NonNull<u8>,
+}
+
+impl Stack {
+    const LAYOUT: Layout = match Layout::from_size_align(STACK_SIZE, 16) {
+        Ok(layout) => layout,
+        Err(_) => panic!("invalid stack layout"),
+    };
+
+    fn new() -> Stack {
+        let bottom = unsafe { alloc(Self::LAYOUT) };
+        Stack {
+            bottom: NonNull::new(bottom).expect("allocating thread stack"),
+        }
+    }
+
+    fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.bottom.as_ptr()) + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.bottom.as_ptr(), Self::LAYOUT) }
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// the processor this thread runs on.
+    cpu: u32,
+    state: AtomicU8,
+    /// set by `unpark`, so that the next `park` returns immediately.
+    notified: AtomicBool,
+    context: UnsafeCell<Context>,
+    /// `None` for threads adopted by `init`, which run on the boot stack.
+    _stack: Option<Stack>,
+    entry: Cell<Option<Box<dyn FnOnce() + Send>>>,
+    /// where a panic on this thread continues, see [`super::panic`].
+    pub(super) catch: Cell<Option<NonNull<super::panic::Catch>>>,
+    /// the task locals of the task this thread is polling, `None` outside of polls.
+    pub(super) locals: Cell<Option<NonNull<super::local::Locals>>>,
+}
+
+// SAFETY: `context`, `entry`, `catch` and `locals` are only accessed by the thread itself,
+// or by its processor with interrupts disabled.
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(name: &'static str, stack: Option<Stack>, entry: Option<Box<dyn FnOnce() + Send>>) -> Arc<Thread> {
+        let context = match &stack {
+            Some(stack) => unsafe { Context::new(stack.top(), thread_start) },
+            None => Context::empty(),
+        };
+        Arc::new(Thread {
+            id: ThreadId::new(),
+            name,
+            cpu: cores::id(),
+            state: AtomicU8::new(State::Running as u8),
+            notified: AtomicBool::new(false),
+            context: UnsafeCell::new(context),
+            _stack: stack,
+            entry: Cell::new(entry),
+            catch: Cell::new(None),
+            locals: Cell::new(None),
+        })
+    }
+
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the number of the processor this thread runs on.
+    #[inline]
+    pub fn cpu(&self) -> u32 {
+        self.cpu
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Makes the thread runnable again if it is parked, otherwise makes its
+    /// next call to [`park`] return immediately.
+    pub fn unpark(self: &Arc<Self>) {
+        let mut queue = RUN_QUEUE.get_for(self.cpu).lock();
+        self.notified.store(true, Ordering::Release);
+        if self.state() == State::Blocked {
+            self.set_state(State::Ready);
+            queue.push_back(self.clone());
+            drop(queue);
+
+            if cores::try_id() != Some(self.cpu) {
+                ipi::reschedule(self.cpu);
+            }
+        }
+    }
+}
+
+percpu! {
+    static CURRENT: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    static IDLE: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    /// the thread that was switched away from, dropped by the next thread
+    /// once it is no longer running on its stack.
+    static PREVIOUS: Cell<Option<Arc<Thread>>> = Cell::new(None);
+    static RUN_QUEUE: IrqSpinLock<VecDeque<Arc<Thread>>> = IrqSpinLock::new(VecDeque::new());
+    /// timer interrupts left until the running thread is preempted.
+    static SLICE_LEFT: Cell<u32> = Cell::new(TIME_SLICE);
+}
+
+/// Adopts the running code as the first thread of this processor, named
+/// `name`, and creates the idle thread.
+///
+/// Must be called once on every processor.
+pub fn init(name: &'static str) {
+    let idle = Thread::new("idle", Some(Stack::new()), Some(Box::new(idle)));
+    without_interrupts(|| {
+        *IDLE.get().borrow_mut() = Some(idle);
+        *CURRENT.get().borrow_mut() = Some(Thread::new(name, None, None));
+    })
+}
+
+fn idle() {
+    loop {
+        interrupts::disable();
+        if RUN_QUEUE.get().lock().is_empty() {
+            enable_and_hlt();
+        } else {
+            schedule();
+            interrupts::enable();
+        }
+    }
+}
+
+/// Returns the running thread.
+pub fn current() -> Arc<Thread> {
+    try_current().expect("threads are not initialized on this processor")
+}
+
+/// Like [`current`], but returns `None` before [`init`] ran on this processor.
+pub fn try_current() -> Option<Arc<Thread>> {
+    cores::try_id()?;
+    without_interrupts(|| CURRENT.get().try_borrow().ok()?.clone())
+}
+
+/// Spawns a thread on this processor that runs `f`.
+pub fn spawn<F>(name: &'static str, f: F) -> Arc<Thread>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new(name, Some(Stack::new()), Some(Box::new(f)));
+    thread.set_state(State::Ready);
+    RUN_QUEUE.get().lock().push_back(thread.clone());
+    thread
+}
+
+/// Lets other ready threads on this processor run first. Returns whether
+/// another thread ran.
+pub fn yield_now() -> bool {
+    without_interrupts(schedule)
+}
+
+/// Blocks the running thread until [`Thread::unpark`] is called on it.
+///
+/// Like `std::thread::park`, this may return spuriously.
+pub fn park() {
+    without_interrupts(|| {
+        let me = current();
+        {
+            let _queue = RUN_QUEUE.get().lock();
+            if me.notified.swap(false, Ordering::Acquire) {
+                return;
+            }
+            me.set_state(State::Blocked);
+        }
+        schedule();
+        me.notified.store(false, Ordering::Relaxed);
+    })
+}
+
+/// Terminates the running thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    current().set_state(State::Exited);
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Called on every timer interrupt, after the end of interrupt was signalled.
+///
+/// Switches to another thread when the time slice of the running one is up.
+pub fn tick() {
+    let left = SLICE_LEFT.get();
+    if left.get() > 1 {
+        left.set(left.get() - 1);
+    } else {
+        preempt();
+    }
+}
+
+/// Switches to another ready thread, if there is one.
+///
+/// Called with interrupts disabled, at the end of interrupt handlers.
+pub fn preempt() {
+    if CURRENT.get().borrow().is_some() {
+        schedule();
+    }
+}
+
+/// Switches to the next ready thread, putting the running thread back into the
+/// run queue unless it blocked or exited. Returns whether another thread ran.
+///
+/// Interrupts must be disabled.
+fn schedule() -> bool {
+    debug_assert!(!interrupts::are_enabled());
+    SLICE_LEFT.get().set(TIME_SLICE);
+
+    let me = current();
+    let idle = IDLE.get().borrow().clone().unwrap();
+    let next = {
+        let mut queue = RUN_QUEUE.get().lock();
+        // a blocked thread that is already unparked is in the queue, and
+        // the idle thread is never queued.
+        if me.state() == State::Running && !Arc::ptr_eq(&me, &idle) {
+            if queue.is_empty() {
+                return false;
+            }
+            me.set_state(State::Ready);
+            queue.push_back(me.clone());
+        }
+        queue.pop_front().unwrap_or(idle)
+    };
+
+    next.set_state(State::Running);
+    if Arc::ptr_eq(&me, &next) {
+        return false;
+    }
+
+    let old = me.context.get();
+    let new = next.context.get();
+    let prev = CURRENT.get().replace(Some(next)).unwrap();
+    drop(me);
+    PREVIOUS.get().set(Some(prev));
+    // SAFETY: interrupts are disabled and `next` was switched out or never ran.
+    unsafe { context::switch(old, new) };
+
+    finish_switch();
+    true
+}
+
+/// Runs right after switching to a thread.
+fn finish_switch() {
+    // the previous thread is off its stack now, so an exited thread can be freed.
+    drop(PREVIOUS.get().take());
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let me = current();
+    let entry = me.entry.take().unwrap();
+    interrupts::enable();
+    if let Err(message) = super::panic::catch(entry) {
+        emergency_sprintln!("thread {} panicked: {}", me.name, message);
+    }
+    drop(me);
+    exit();
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use super::{current, park, spawn, yield_now, State, Thread};
+    use crate::testing::kernel_test;
+
+    /// Yields until `thread` exited, which the timer may also switch to.
+    fn join(thread: &Thread) {
+        for _ in 0..100 {
+            if thread.state() == State::Exited {
+                return;
+            }
+            yield_now();
+        }
+        panic!("thread did not exit");
+    }
+
+    #[kernel_test]
+    fn spawned_thread_runs() {
+        let me = current().id();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let thread = spawn("test", move || {
+            assert_ne!(current().id(), me);
+            flag.store(true, Ordering::Relaxed);
+        });
+        join(&thread);
+        assert!(ran.load(Ordering::Relaxed));
+    }
+
+    #[kernel_test]
+    fn threads_interleave() {
+        let count = Arc::new(AtomicU32::new(0));
+        let other = count.clone();
+        let thread = spawn("test", move || {
+            for _ in 0..3 {
+                other.fetch_add(1, Ordering::Relaxed);
+                yield_now();
+            }
+        });
+        for _ in 0..3 {
+            count.fetch_add(1, Ordering::Relaxed);
+            yield_now();
+        }
+        join(&thread);
+        assert_eq!(count.load(Ordering::Relaxed), 6);
+    }
+
+    #[kernel_test]
+    fn unpark_wakes() {
+        let parent = current();
+        let thread = spawn("test", move || parent.unpark());
+        // may return spuriously, but only after the thread ran.
+        park();
+        join(&thread);
+    }
+}
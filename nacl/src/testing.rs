@@ -0,0 +1,166 @@
+//! The kernel test harness.
+//!
+//! Tests are functions marked with [`kernel_test`]. `cargo test` builds a
+//! kernel that runs them after booting, instead of starting the executor. Every
+//! test runs inside [`panic::catch`], so tests can panic or fault without
+//! taking the others down. The results are reported over the serial port, in
+//! the format of `libtest`:
+//!
+//! ```text
+//! running 2 tests
+//! test nacl::task::map::tests::insert ... ok
+//! test nacl::task::rcu::tests::deferred_runs ... FAILED
+//!     panicked: deferred function did not run
+//! test result: FAILED. 1 passed; 1 failed
+//! ```
+//!
+//! QEMU then exits through its `isa-debug-exit` device, with
+//! [`QemuExitCode::Success`] if all tests passed.
+
+use alloc::format;
+use alloc::string::String;
+
+pub use nacl_macros::kernel_test;
+pub use x86_64::structures::idt::ExceptionVector;
+
+use crate::arch::{self, exit_qemu, QemuExitCode};
+use crate::task::panic;
+use crate::{serial, sprintln};
+
+/// What a test is expected to do.
+#[derive(Debug, Clone, Copy)]
+pub enum Expect {
+    Pass,
+    /// panic, with a message that contains the string if there is one.
+    Panic(Option<&'static str>),
+    /// cause an exception, like a page fault, this one if there is one.
+    Fault(Option<ExceptionVector>),
+}
+
+/// A test, created by [`kernel_test`].
+pub struct KernelTest {
+    pub name: &'static str,
+    pub run: fn(),
+    pub expect: Expect,
+}
+
+impl KernelTest {
+    /// Runs the test, returning why it failed if it did.
+    fn check(&self) -> Result<(), String> {
+        let faults = arch::faults();
+        let result = panic::catch(self.run);
+        let faulted = arch::faults() != faults;
+        match (self.expect, result) {
+            (Expect::Pass, Ok(())) => Ok(()),
+            (Expect::Pass, Err(message)) => Err(format!("panicked: {}", message)),
+            (Expect::Panic(_), Ok(())) => Err("did not panic".into()),
+            (Expect::Panic(_), Err(message)) if faulted => {
+                Err(format!("faulted instead of panicking: {}", message))
+            }
+            (Expect::Panic(Some(expected)), Err(message)) if !message.contains(expected) => {
+                Err(format!(
+                    "panic message {:?} does not contain {:?}",
+                    message, expected
+                ))
+            }
+            (Expect::Panic(_), Err(_)) => Ok(()),
+            (Expect::Fault(_), Ok(())) => Err("did not fault".into()),
+            (Expect::Fault(Some(expected)), Err(_))
+                if faulted && arch::last_fault() != Some(expected) =>
+            {
+                Err(format!(
+                    "raised {:?} instead of {:?}",
+                    arch::last_fault(),
+                    expected
+                ))
+            }
+            (Expect::Fault(_), Err(_)) if faulted => Ok(()),
+            (Expect::Fault(_), Err(message)) => {
+                Err(format!("panicked instead of faulting: {}", message))
+            }
+        }
+    }
+}
+
+/// The test runner, called by `test_main`.
+pub fn run(tests: &[&KernelTest]) -> ! {
+    sprintln!("running {} tests", tests.len());
+    let mut failed = 0;
+    for test in tests {
+        // printed afterwards, so it is not interrupted by panic messages.
+        match test.check() {
+            Ok(()) => sprintln!("test {} ... ok", test.name),
+            Err(reason) => {
+                failed += 1;
+                sprintln!("test {} ... FAILED\n    {}", test.name, reason);
+            }
+        }
+    }
+
+    let passed = tests.len() - failed;
+    let (result, code) = if failed == 0 {
+        ("ok", QemuExitCode::Success)
+    } else {
+        ("FAILED", QemuExitCode::Failed)
+    };
+    sprintln!(
+        "test result: {}. {} passed; {} failed",
+        result,
+        passed,
+        failed
+    );
+    serial::flush();
+    exit_qemu(code)
+}
+
+/// Called by the panic handler for panics that cannot be caught, which end the
+/// test run.
+pub fn uncaught_panic() -> ! {
+    sprintln!("test result: FAILED. uncaught panic");
+    serial::flush();
+    exit_qemu(QemuExitCode::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::arch::asm;
+    use core::ptr;
+
+    use super::kernel_test;
+
+    #[kernel_test]
+    fn passes() {}
+
+    #[kernel_test(should_panic)]
+    fn panics() {
+        panic!("expected");
+    }
+
+    #[kernel_test(should_panic = "with message")]
+    fn panics_with_message() {
+        panic!("a panic with message");
+    }
+
+    #[kernel_test(should_fault = Page)]
+    fn page_fault() {
+        // the null page is never mapped.
+        unsafe { ptr::read_volatile(8 as *const u64) };
+    }
+
+    #[kernel_test(should_fault = GeneralProtection)]
+    fn general_protection_fault() {
+        // not canonical with either 4 or 5 level paging.
+        unsafe { ptr::read_volatile(0x0100_0000_0000_0000 as *const u64) };
+    }
+
+    #[kernel_test(should_fault = InvalidOpcode)]
+    fn invalid_opcode() {
+        unsafe { asm!("ud2") };
+    }
+
+    #[kernel_test]
+    async fn async_test() {
+        let (a, b) = crate::task::combinator::join(async { 1 }, async { 2 }).await;
+        assert_eq!(a + b, 3);
+    }
+}
@@ -0,0 +1,238 @@
+//! Building the kernel with cargo.
+//!
+//! Cargo is asked for JSON messages on its standard output. Compiler
+//! diagnostics are printed as cargo would print them, and the artifacts tell
+//! where the kernel ended up.
+//!
+//! The kernel is built with `cargo rustc`, which passes the flags of the kernel
+//! to the kernel crate alone and leaves the rustflags of the user, from the
+//! environment or the cargo configuration, to cargo.
+
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, io};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+/// Name of the kernel package and executable.
+const KERNEL: &str = "nacl";
+const TARGET: &str = "x86_64-unknown-none";
+/// Flags the kernel crate is built with, besides those of the user.
+const KERNEL_RUSTFLAGS: &[&str] = &["-C", "relocation-model=static"];
+
+/// A message of `cargo --message-format=json`. Only the fields needed here
+/// are parsed.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message {
+    CompilerArtifact(Artifact),
+    #[serde(rename = "compiler-message")]
+    Diagnostic {
+        message: Diagnostic,
+    },
+    BuildFinished {
+        success: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct Artifact {
+    target: Target,
+    profile: Profile,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    name: String,
+    kind: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Profile {
+    test: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Diagnostic {
+    rendered: Option<String>,
+}
+
+impl Artifact {
+    /// Whether this is the kernel executable, or its tests if `test` is set.
+    fn is_kernel(&self, test: bool) -> bool {
+        self.target.name == KERNEL
+            && self.target.kind.iter().any(|kind| kind == "bin")
+            && self.profile.test == test
+    }
+}
+
+/// The profile `cargo rustc` builds the kernel with. The test and bench profiles
+/// build the test harness.
+fn profile(test: bool, release: bool) -> &'static str {
+    match (test, release) {
+        (false, false) => "dev",
+        (false, true) => "release",
+        (true, false) => "test",
+        (true, true) => "bench",
+    }
+}
+
+/// Builds the kernel, or its tests if `test` is set, with the release profile
+/// if `release` is set. Returns the path of the executable.
+pub fn build_kernel(test: bool, release: bool) -> anyhow::Result<PathBuf> {
+    let cargo = env::var("CARGO").context("`CARGO` is not set, run this through `cargo run`")?;
+    let mut child = Command::new(cargo)
+        .arg("rustc")
+        .arg("--bin")
+        .arg(KERNEL)
+        .arg(format!("--profile={}", profile(test, release)))
+        .arg("--target")
+        .arg(TARGET)
+        .arg(if io::stderr().is_terminal() {
+            "--message-format=json-diagnostic-rendered-ansi"
+        } else {
+            "--message-format=json"
+        })
+        .arg("--")
+        .args(KERNEL_RUSTFLAGS)
+        .current_dir(Path::new("./nacl").canonicalize()?)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("running cargo")?;
+
+    let mut executables = Vec::new();
+    let mut success = false;
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        // build scripts may print to standard output too.
+        let Ok(message) = serde_json::from_str::<Message>(&line) else {
+            println!("{line}");
+            continue;
+        };
+        match message {
+            Message::CompilerArtifact(artifact) if artifact.is_kernel(test) => {
+                executables.extend(artifact.executable);
+            }
+            Message::Diagnostic { message } => {
+                if let Some(rendered) = message.rendered {
+                    eprint!("{rendered}");
+                }
+            }
+            Message::BuildFinished { success: finished } => success = finished,
+            Message::CompilerArtifact(_) | Message::Other => {}
+        }
+    }
+
+    let status = child.wait()?;
+    if !success || !status.success() {
+        bail!("building the kernel failed: {status}");
+    }
+    executables.dedup();
+    match <[PathBuf; 1]>::try_from(executables) {
+        Ok([executable]) => Ok(executable),
+        Err(executables) if executables.is_empty() => bail!("no kernel executable was built"),
+        Err(executables) => bail!("several kernel executables were built: {executables:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn parse(line: &str) -> Message {
+        serde_json::from_str(line).unwrap()
+    }
+
+    fn artifact(line: &str) -> Artifact {
+        match parse(line) {
+            Message::CompilerArtifact(artifact) => artifact,
+            message => panic!("not an artifact: {message:?}"),
+        }
+    }
+
+    const KERNEL_ARTIFACT: &str = r#"{"reason":"compiler-artifact","package_id":"path+file:///home/user/nacl/nacl#0.1.0","manifest_path":"/home/user/nacl/nacl/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"nacl","src_path":"/home/user/nacl/nacl/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":[],"filenames":["/home/user/nacl/target/x86_64-unknown-none/release/nacl"],"executable":"/home/user/nacl/target/x86_64-unknown-none/release/nacl","fresh":false}"#;
+    const KERNEL_TEST_ARTIFACT: &str = r#"{"reason":"compiler-artifact","package_id":"path+file:///home/user/nacl/nacl#0.1.0","manifest_path":"/home/user/nacl/nacl/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"nacl","src_path":"/home/user/nacl/nacl/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":true},"features":[],"filenames":["/home/user/nacl/target/x86_64-unknown-none/debug/deps/nacl-26705e919b3c41b1"],"executable":"/home/user/nacl/target/x86_64-unknown-none/debug/deps/nacl-26705e919b3c41b1","fresh":false}"#;
+
+    #[test]
+    fn kernel_artifacts() {
+        let kernel = artifact(KERNEL_ARTIFACT);
+        assert!(kernel.is_kernel(false));
+        assert!(!kernel.is_kernel(true));
+        let tests = artifact(KERNEL_TEST_ARTIFACT);
+        assert!(tests.is_kernel(true));
+        assert!(!tests.is_kernel(false));
+        assert_eq!(
+            tests.executable.as_deref(),
+            Some(Path::new(
+                "/home/user/nacl/target/x86_64-unknown-none/debug/deps/nacl-26705e919b3c41b1"
+            ))
+        );
+    }
+
+    #[test]
+    fn escaped_path() {
+        let line = r#"{"reason":"compiler-artifact","package_id":"path+file:///home/user/my%20%22nacl%22/nacl#0.1.0","manifest_path":"/home/user/my \"nacl\"/nacl/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"nacl","src_path":"/home/user/my \"nacl\"/nacl/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"3","debuginfo":0,"debug_assertions":false,"overflow_checks":false,"test":false},"features":[],"filenames":["/home/user/my \"nacl\"/target/x86_64-unknown-none/release/nacl"],"executable":"/home/user/my \"nacl\"/kérnel\\target/x86_64-unknown-none/release/nacl","fresh":true}"#;
+        assert_eq!(
+            artifact(line).executable.as_deref(),
+            Some(Path::new(
+                "/home/user/my \"nacl\"/kérnel\\target/x86_64-unknown-none/release/nacl"
+            ))
+        );
+    }
+
+    #[test]
+    fn build_script_artifact() {
+        let line = r#"{"reason":"compiler-artifact","package_id":"path+file:///home/user/nacl/nacl#0.1.0","manifest_path":"/home/user/nacl/nacl/Cargo.toml","target":{"kind":["custom-build"],"crate_types":["bin"],"name":"build-script-build","src_path":"/home/user/nacl/nacl/build.rs","edition":"2021","doc":false,"doctest":false,"test":false},"profile":{"opt_level":"0","debuginfo":0,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/home/user/nacl/target/release/build/nacl-3ebcd3febdd9ebd8/build-script-build"],"executable":null,"fresh":false}"#;
+        let build_script = artifact(line);
+        assert!(!build_script.is_kernel(false));
+        assert!(!build_script.is_kernel(true));
+        assert_eq!(build_script.executable, None);
+    }
+
+    #[test]
+    fn compiler_message() {
+        let line = r#"{"reason":"compiler-message","package_id":"path+file:///home/user/nacl/nacl#0.1.0","manifest_path":"/home/user/nacl/nacl/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"nacl","src_path":"/home/user/nacl/nacl/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"message":{"rendered":"warning: unused variable: `x`\n --> src/main.rs:1:17\n  |\n1 | fn main() { let x = 1; }\n  |                 ^ help: if this is intentional, prefix it with an underscore: `_x`\n  |\n  = note: `#[warn(unused_variables)]` on by default\n\n","$message_type":"diagnostic","children":[{"children":[],"code":null,"level":"note","message":"`#[warn(unused_variables)]` on by default","rendered":null,"spans":[]}],"level":"warning","message":"unused variable: `x`","spans":[{"byte_end":17,"byte_start":16,"column_end":18,"column_start":17,"expansion":null,"file_name":"src/main.rs","is_primary":true,"label":null,"line_end":1,"line_start":1,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":18,"highlight_start":17,"text":"fn main() { let x = 1; }"}]}],"code":{"code":"unused_variables","explanation":null}}}"#;
+        let Message::Diagnostic { message } = parse(line) else {
+            panic!("not a diagnostic");
+        };
+        assert!(message
+            .rendered
+            .unwrap()
+            .starts_with("warning: unused variable: `x`\n --> src/main.rs:1:17\n"));
+    }
+
+    #[test]
+    fn build_finished() {
+        assert!(matches!(
+            parse(r#"{"reason":"build-finished","success":false}"#),
+            Message::BuildFinished { success: false }
+        ));
+    }
+
+    #[test]
+    fn other_reasons() {
+        let line = r#"{"reason":"build-script-executed","package_id":"path+file:///home/user/nacl/nacl#0.1.0","linked_libs":[],"linked_paths":[],"cfgs":[],"env":[],"out_dir":"/home/user/nacl/target/release/build/nacl-ac1c5646544bb1fc/out"}"#;
+        assert!(matches!(parse(line), Message::Other));
+        assert!(matches!(
+            parse(r#"{"reason":"some-future-reason","field":[1,2,3]}"#),
+            Message::Other
+        ));
+        // what build scripts print is not a message.
+        assert!(serde_json::from_str::<Message>("cargo:warning=hello").is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        assert_eq!(profile(false, false), "dev");
+        assert_eq!(profile(false, true), "release");
+        assert_eq!(profile(true, false), "test");
+        assert_eq!(profile(true, true), "bench");
+    }
+}
@@ -0,0 +1,268 @@
+//! Command-line arguments of `nacl_boot`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+pub const USAGE: &str = "\
+Builds and boots the NaCl kernel.
+
+Usage: nacl_boot [COMMAND] [OPTIONS] [-- QEMU_ARGS...]
+
+Commands:
+  build   Build the kernel
+  image   Build the kernel and a bootable GPT disk image with Limine
+  run     Build the kernel and boot it in QEMU (the default)
+  test    Build the kernel tests and run them in QEMU without a display
+  gdb     Like `run`, but wait for a debugger on localhost:1234
+
+Options:
+  --release             Build with the release profile (default, except for `test`)
+  --debug               Build with the dev profile (default for `test`)
+  --smp <N>             Number of processors [default: up to 4]
+  -m, --memory <SIZE>   Memory size, like `512M` or `2G` [default: 1G]
+  --accel <ACCEL>       `kvm`, `tcg` or `auto` for KVM when available [default: auto]
+  --firmware <PATH>     UEFI firmware, like OVMF_CODE.fd [default: searched]
+  --cmdline <ARGS>      Kernel command line
+  --iso                 Make a hybrid BIOS and UEFI ISO image instead of a disk image
+  --bios                Boot the ISO image with SeaBIOS instead of UEFI
+  --timeout <SECS>      Time limit of `test` [default: 300]
+  -h, --help            Print this help
+
+Arguments after `--` are passed to QEMU as they are.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    Build,
+    Image,
+    Run,
+    Test,
+    Gdb,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accel {
+    /// KVM if `/dev/kvm` can be opened, TCG otherwise.
+    Auto,
+    Kvm,
+    Tcg,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Subcommand,
+    pub release: bool,
+    pub smp: usize,
+    pub memory: String,
+    pub accel: Accel,
+    pub firmware: Option<PathBuf>,
+    pub cmdline: Option<String>,
+    /// make and boot an ISO image rather than a disk image.
+    pub iso: bool,
+    /// boot with BIOS rather than UEFI.
+    pub bios: bool,
+    pub timeout: Duration,
+    pub qemu_args: Vec<String>,
+}
+
+impl Options {
+    /// Parses `args`, without the executable name. Returns `None` if help was
+    /// asked for.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Options>> {
+        let mut args = args.into_iter().peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("build") => Some(Subcommand::Build),
+            Some("image") => Some(Subcommand::Image),
+            Some("run") => Some(Subcommand::Run),
+            Some("test") => Some(Subcommand::Test),
+            Some("gdb") => Some(Subcommand::Gdb),
+            _ => None,
+        };
+        if command.is_some() {
+            args.next();
+        }
+        let mut command = command.unwrap_or(Subcommand::Run);
+
+        let mut release = None;
+        let mut smp = num_cpus::get().min(4);
+        let mut memory = "1G".to_owned();
+        let mut accel = Accel::Auto;
+        let mut firmware = None;
+        let mut cmdline = None;
+        let mut iso = false;
+        let mut bios = false;
+        let mut timeout = None;
+        let mut qemu_args = Vec::new();
+
+        while let Some(arg) = args.next() {
+            // `--option=value` and `--option value` are the same.
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_owned())),
+                _ => (arg.as_str(), None),
+            };
+            let flag = matches!(
+                name,
+                "-h" | "--help" | "--release" | "--debug" | "--iso" | "--bios" | "--no-run" | "--"
+            );
+            if flag && inline.is_some() {
+                bail!("`{name}` takes no value");
+            }
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .with_context(|| format!("`{name}` needs a value"))
+            };
+            match name {
+                "-h" | "--help" => return Ok(None),
+                "--release" => release = Some(true),
+                "--debug" => release = Some(false),
+                "--smp" => {
+                    let n = value()?;
+                    smp = n
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .with_context(|| format!("invalid number of processors `{n}`"))?;
+                }
+                "-m" | "--memory" => memory = value()?,
+                "--accel" => {
+                    accel = match value()?.as_str() {
+                        "auto" => Accel::Auto,
+                        "kvm" => Accel::Kvm,
+                        "tcg" => Accel::Tcg,
+                        other => bail!("unknown accelerator `{}`", other),
+                    }
+                }
+                "--firmware" => firmware = Some(PathBuf::from(value()?)),
+                "--cmdline" => cmdline = Some(value()?),
+                "--iso" => iso = true,
+                "--bios" => bios = true,
+                "--timeout" => {
+                    let secs = value()?;
+                    timeout = Some(Duration::from_secs(
+                        secs.parse()
+                            .with_context(|| format!("invalid timeout `{secs}`"))?,
+                    ));
+                }
+                // the old way of asking for `image`.
+                "--no-run" if command == Subcommand::Run => command = Subcommand::Image,
+                "--" => qemu_args.extend(args.by_ref()),
+                other => bail!("unexpected argument `{}`\n\n{}", other, USAGE),
+            }
+        }
+
+        if timeout.is_some() && command != Subcommand::Test {
+            bail!("`--timeout` only applies to `test`");
+        }
+        if bios && firmware.is_some() {
+            bail!("`--bios` boots with SeaBIOS, and takes no `--firmware`");
+        }
+
+        Ok(Some(Options {
+            command,
+            release: release.unwrap_or(command != Subcommand::Test),
+            smp,
+            memory,
+            accel,
+            firmware,
+            cmdline,
+            // only the ISO image boots with BIOS.
+            iso: iso || bios,
+            bios,
+            timeout: timeout.unwrap_or(Duration::from_secs(300)),
+            qemu_args,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Option<Options>> {
+        Options::parse(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    fn options(args: &[&str]) -> Options {
+        parse(args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let run = options(&[]);
+        assert_eq!(run.command, Subcommand::Run);
+        assert!(run.release);
+        assert!(!run.iso);
+        assert_eq!(run.memory, "1G");
+        let test = options(&["test"]);
+        assert!(!test.release);
+        assert_eq!(test.timeout, Duration::from_secs(300));
+        assert!(parse(&["--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn values() {
+        for args in [&["--smp=3"][..], &["--smp", "3"]] {
+            assert_eq!(options(args).smp, 3);
+        }
+        assert_eq!(options(&["-m", "2G"]).memory, "2G");
+        assert_eq!(options(&["--memory=512M"]).memory, "512M");
+        // only the first `=` separates the value.
+        assert_eq!(
+            options(&["--cmdline=log=debug"]).cmdline.as_deref(),
+            Some("log=debug")
+        );
+        assert_eq!(options(&["--accel", "tcg"]).accel, Accel::Tcg);
+        assert_eq!(
+            options(&["test", "--timeout=5"]).timeout,
+            Duration::from_secs(5)
+        );
+
+        assert!(parse(&["--smp"]).is_err());
+        assert!(parse(&["--smp=0"]).is_err());
+        assert!(parse(&["--accel=xen"]).is_err());
+        assert!(parse(&["--timeout", "5"]).is_err());
+    }
+
+    #[test]
+    fn flags_take_no_value() {
+        for arg in [
+            "--iso=anything",
+            "--release=false",
+            "--help=me",
+            "--no-run=1",
+            "--=x",
+        ] {
+            assert!(parse(&[arg]).is_err(), "{arg}");
+        }
+        // nor do they take the next argument.
+        assert!(parse(&["--release", "false"]).is_err());
+        assert!(!options(&["--release", "--debug"]).release);
+    }
+
+    #[test]
+    fn no_run() {
+        assert_eq!(options(&["--no-run"]).command, Subcommand::Image);
+        assert_eq!(options(&["run", "--no-run"]).command, Subcommand::Image);
+        assert!(parse(&["gdb", "--no-run"]).is_err());
+    }
+
+    #[test]
+    fn qemu_args() {
+        let options = options(&["image", "--iso", "--", "-d", "int", "--smp=8", "--"]);
+        assert_eq!(options.command, Subcommand::Image);
+        assert!(options.iso);
+        assert_eq!(options.qemu_args, ["-d", "int", "--smp=8", "--"]);
+        assert_ne!(options.smp, 8);
+    }
+
+    #[test]
+    fn bios() {
+        let options = options(&["--bios"]);
+        assert!(options.bios);
+        assert!(options.iso);
+        assert!(parse(&["--bios", "--firmware", "OVMF.fd"]).is_err());
+    }
+}
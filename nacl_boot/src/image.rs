@@ -0,0 +1,120 @@
+//! Bootable disk images.
+//!
+//! The image has a GPT with a single EFI system partition, which holds the
+//! files put together by [`create_disk_images`](crate::create_disk_images).
+//! UEFI firmware boots `EFI/BOOT/BOOTX64.EFI` from it, so the image boots in
+//! QEMU as well as from a USB stick it is written to.
+
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Context;
+use gpt::disk::LogicalBlockSize;
+
+const MB: u64 = 1024 * 1024;
+/// Room for the file system structures, besides the files themselves.
+const FAT_OVERHEAD: u64 = MB;
+/// Partitions start at a multiple of this many blocks, so 1 MiB.
+const PARTITION_ALIGNMENT: u64 = 2048;
+const BLOCK_SIZE: LogicalBlockSize = LogicalBlockSize::Lb512;
+
+/// Total size of the files under `dir`.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Copies the contents of the host directory `src` into `dest`.
+fn copy_dir<T: fatfs::ReadWriteSeek>(src: &Path, dest: &fatfs::Dir<'_, T>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_str().context("file name is not UTF-8")?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest.create_dir(name)?)?;
+        } else {
+            let mut file = dest.create_file(name)?;
+            file.truncate()?;
+            io::copy(&mut File::open(entry.path())?, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a FAT file system at `out` holding the contents of `root`.
+pub fn create_fat_filesystem(root: &Path, out: &Path) -> anyhow::Result<()> {
+    let size = (dir_size(root)? + FAT_OVERHEAD).div_ceil(MB) * MB;
+    let fat_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out)?;
+    fat_file.set_len(size)?;
+
+    let options = fatfs::FormatVolumeOptions::new().volume_label(*b"NACL       ");
+    fatfs::format_volume(&fat_file, options).context("formatting the FAT file system")?;
+    let filesystem = fatfs::FileSystem::new(&fat_file, fatfs::FsOptions::new())?;
+    copy_dir(root, &filesystem.root_dir())?;
+    filesystem.unmount()?;
+    Ok(())
+}
+
+/// Creates a GPT disk image at `out` whose only partition is the EFI system
+/// partition `fat_image`.
+pub fn create_gpt_disk(fat_image: &Path, out: &Path) -> anyhow::Result<()> {
+    let mut disk = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out)?;
+
+    // the partition table comes before the partition, and its backup after.
+    let partition_size = fs::metadata(fat_image)?.len();
+    let disk_size = MB + partition_size + MB;
+    disk.set_len(disk_size)?;
+
+    let blocks = disk_size / u64::from(BLOCK_SIZE);
+    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(u32::try_from(blocks - 1).unwrap_or(u32::MAX));
+    mbr.overwrite_lba0(&mut disk)?;
+
+    let mut gpt = gpt::GptConfig::new()
+        .writable(true)
+        .initialized(false)
+        .logical_block_size(BLOCK_SIZE)
+        .create_from_device(Box::new(&mut disk), None)?;
+    gpt.update_partitions(Default::default())?;
+    let id = gpt.add_partition(
+        "NaCl",
+        partition_size,
+        gpt::partition_types::EFI,
+        0,
+        Some(PARTITION_ALIGNMENT),
+    )?;
+    let start = gpt.partitions()[&id].bytes_start(BLOCK_SIZE)?;
+    gpt.write()?;
+
+    disk.seek(SeekFrom::Start(start))?;
+    io::copy(&mut File::open(fat_image)?, &mut disk)?;
+    Ok(())
+}
+
+/// Creates a bootable disk image at `out` from the files in `root`.
+pub fn create_disk_image(root: &Path, out: &Path) -> anyhow::Result<()> {
+    let fat_image = out.with_extension("fat");
+    create_fat_filesystem(root, &fat_image)?;
+    create_gpt_disk(&fat_image, out)?;
+    fs::remove_file(&fat_image)?;
+    Ok(())
+}
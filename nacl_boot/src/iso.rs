@@ -0,0 +1,693 @@
+//! Bootable ISO 9660 images.
+//!
+//! The image boots as a CD with both firmware types, through El Torito: BIOS
+//! loads `limine-bios-cd.bin` without emulation, and UEFI boots the FAT image
+//! `limine-uefi-cd.bin`. The first sector also holds an MBR with a partition
+//! covering that FAT image, so UEFI firmware boots the image from a USB stick
+//! too.
+//!
+//! File names follow ISO 9660 level 2, and Rock Ridge `NM` entries keep the
+//! original names, which is what Limine and most systems read. Dates are left
+//! unspecified, so the same files always give the same image.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+const SECTOR_SIZE: u64 = 2048;
+/// The first 16 sectors are left to the system, here the MBR.
+const SYSTEM_AREA: u32 = 16;
+const PRIMARY_VOLUME_DESCRIPTOR: u32 = SYSTEM_AREA;
+const BOOT_RECORD: u32 = SYSTEM_AREA + 1;
+const TERMINATOR: u32 = SYSTEM_AREA + 2;
+const PATH_TABLES: u32 = SYSTEM_AREA + 3;
+
+/// Loaded by BIOS firmware, and patched with a boot info table.
+const BIOS_BOOT_FILE: &str = "limine-bios-cd.bin";
+/// The FAT image UEFI firmware boots from.
+const UEFI_BOOT_IMAGE: &str = "limine-uefi-cd.bin";
+/// Number of 512 byte sectors of `BIOS_BOOT_FILE` the firmware loads.
+const BIOS_LOAD_SECTORS: u16 = 4;
+/// Where the boot info table goes in `BIOS_BOOT_FILE`.
+const BOOT_INFO_TABLE: std::ops::Range<usize> = 8..64;
+
+/// Longest directory record, which must have an even length that fits a byte.
+const MAX_RECORD_LEN: usize = 254;
+/// Longest file name that fits into a directory record: 33 bytes of fixed
+/// fields, a level 2 identifier of up to 33 bytes, a 36 byte `PX` entry and an
+/// `NM` entry with 5 bytes besides the name.
+const MAX_NAME_LEN: usize = MAX_RECORD_LEN - 33 - 33 - 36 - 5;
+
+const ROCK_RIDGE_ID: &[u8] = b"RRIP_1991A";
+const ROCK_RIDGE_DESCRIPTION: &[u8] =
+    b"THE ROCK RIDGE INTERCHANGE PROTOCOL PROVIDES SUPPORT FOR POSIX FILE SYSTEM SEMANTICS";
+const ROCK_RIDGE_SOURCE: &[u8] = b"NACL_BOOT";
+
+fn both16(v: u16) -> [u8; 4] {
+    let [a, b] = v.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both32(v: u32) -> [u8; 8] {
+    let [a, b, c, d] = v.to_le_bytes();
+    [a, b, c, d, d, c, b, a]
+}
+
+fn sectors(bytes: u64) -> u32 {
+    bytes.div_ceil(SECTOR_SIZE) as u32
+}
+
+/// A file or directory in the image.
+#[derive(Debug)]
+struct Node {
+    name: String,
+    iso_name: Vec<u8>,
+    parent: usize,
+    /// `None` for directories.
+    source: Option<PathBuf>,
+    children: Vec<usize>,
+    lba: u32,
+    size: u32,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.source.is_none()
+    }
+}
+
+/// Turns `name` into a level 2 identifier: 31 characters for directories, and
+/// 30 for the name and extension of files.
+fn iso_name(name: &str, dir: bool) -> Vec<u8> {
+    let mangle = |s: &str, len: usize| -> String {
+        s.chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9' | '_') => c,
+                _ => '_',
+            })
+            .take(len)
+            .collect()
+    };
+    if dir {
+        return mangle(name, 31).into_bytes();
+    }
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let ext = mangle(ext, 8);
+    format!("{}.{};1", mangle(stem, 30 - ext.len()), ext).into_bytes()
+}
+
+/// Builds the tree of nodes under `root`, with the directories in the order
+/// of the path table: by depth, then by parent, then by name.
+fn read_tree(root: &Path) -> anyhow::Result<(Vec<Node>, Vec<usize>)> {
+    let mut nodes = vec![Node {
+        name: String::new(),
+        iso_name: vec![0],
+        parent: 0,
+        source: None,
+        children: Vec::new(),
+        lba: 0,
+        size: 0,
+    }];
+    let mut dirs = Vec::new();
+    let mut queue = VecDeque::from([(0, root.to_owned())]);
+    while let Some((dir, path)) = queue.pop_front() {
+        dirs.push(dir);
+        let mut children = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_str().context("file name is not UTF-8")?.to_owned();
+            // the Rock Ridge name has to fit into the directory record.
+            if name.len() > MAX_NAME_LEN {
+                bail!("`{name}` is longer than {MAX_NAME_LEN} bytes");
+            }
+            let is_dir = entry.file_type()?.is_dir();
+            let size = if is_dir {
+                0
+            } else {
+                let len = entry.metadata()?.len();
+                u32::try_from(len).with_context(|| format!("`{name}` is too large"))?
+            };
+            children.push(Node {
+                iso_name: iso_name(&name, is_dir),
+                name,
+                parent: dir,
+                source: (!is_dir).then(|| entry.path()),
+                children: Vec::new(),
+                lba: 0,
+                size,
+            });
+        }
+        children.sort_by(|a, b| a.iso_name.cmp(&b.iso_name));
+        if let Some(pair) = children.windows(2).find(|w| w[0].iso_name == w[1].iso_name) {
+            bail!(
+                "`{}` and `{}` have the same ISO 9660 name",
+                pair[0].name,
+                pair[1].name
+            );
+        }
+        for child in children {
+            let index = nodes.len();
+            if child.is_dir() {
+                queue.push_back((index, path.join(&child.name)));
+            }
+            nodes[dir].children.push(index);
+            nodes.push(child);
+        }
+    }
+    Ok((nodes, dirs))
+}
+
+/// Rock Ridge `PX` entry, giving the node read-only POSIX permissions.
+fn posix_entry(dir: bool) -> Vec<u8> {
+    let mode = if dir { 0o40555 } else { 0o100444 };
+    let mut entry = vec![b'P', b'X', 36, 1];
+    entry.extend(both32(mode));
+    entry.extend(both32(1)); // links
+    entry.extend(both32(0)); // user
+    entry.extend(both32(0)); // group
+    entry
+}
+
+/// System use entries of the `.` record of the root, which announce the
+/// use of Rock Ridge.
+fn root_entries() -> Vec<u8> {
+    let mut entries = vec![b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+    let len = 8 + ROCK_RIDGE_ID.len() + ROCK_RIDGE_DESCRIPTION.len() + ROCK_RIDGE_SOURCE.len();
+    entries.extend([b'E', b'R', len as u8, 1]);
+    entries.extend([
+        ROCK_RIDGE_ID.len() as u8,
+        ROCK_RIDGE_DESCRIPTION.len() as u8,
+        ROCK_RIDGE_SOURCE.len() as u8,
+        1,
+    ]);
+    entries.extend(ROCK_RIDGE_ID);
+    entries.extend(ROCK_RIDGE_DESCRIPTION);
+    entries.extend(ROCK_RIDGE_SOURCE);
+    entries.extend(posix_entry(true));
+    entries
+}
+
+fn name_entry(name: &str) -> Vec<u8> {
+    let mut entry = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+    entry.extend(name.as_bytes());
+    entry
+}
+
+/// Returns a directory record.
+fn record(
+    identifier: &[u8],
+    lba: u32,
+    size: u32,
+    dir: bool,
+    system_use: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut record = vec![0; 2];
+    record.extend(both32(lba));
+    record.extend(both32(size));
+    record.extend([0; 7]); // date, not specified
+    record.push(if dir { 2 } else { 0 });
+    record.extend([0, 0]);
+    record.extend(both16(1)); // volume sequence number
+    record.push(identifier.len() as u8);
+    record.extend(identifier);
+    if identifier.len() % 2 == 0 {
+        record.push(0);
+    }
+    record.extend(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    if record.len() > MAX_RECORD_LEN {
+        bail!(
+            "directory record of `{}` is {} bytes long",
+            String::from_utf8_lossy(identifier),
+            record.len()
+        );
+    }
+    record[0] = record.len() as u8;
+    Ok(record)
+}
+
+/// Returns the records of directory `dir`, laid out in sectors.
+fn directory(nodes: &[Node], dir: usize) -> anyhow::Result<Vec<u8>> {
+    let this = &nodes[dir];
+    let parent = &nodes[this.parent];
+    let mut records = vec![
+        record(
+            &[0],
+            this.lba,
+            this.size,
+            true,
+            &if dir == 0 {
+                root_entries()
+            } else {
+                posix_entry(true)
+            },
+        )?,
+        record(&[1], parent.lba, parent.size, true, &posix_entry(true))?,
+    ];
+    for &child in &this.children {
+        let child = &nodes[child];
+        let mut system_use = posix_entry(child.is_dir());
+        system_use.extend(name_entry(&child.name));
+        records.push(record(
+            &child.iso_name,
+            child.lba,
+            child.size,
+            child.is_dir(),
+            &system_use,
+        )?);
+    }
+
+    // records may not cross sectors.
+    let mut data = Vec::new();
+    for record in records {
+        let left = SECTOR_SIZE as usize - data.len() % SECTOR_SIZE as usize;
+        if record.len() > left {
+            data.resize(data.len() + left, 0);
+        }
+        data.extend(record);
+    }
+    data.resize(
+        sectors(data.len() as u64) as usize * SECTOR_SIZE as usize,
+        0,
+    );
+    Ok(data)
+}
+
+/// Returns the path table of `dirs`, little endian if `le`.
+fn path_table(nodes: &[Node], dirs: &[usize], le: bool) -> Vec<u8> {
+    let mut table = Vec::new();
+    for &dir in dirs {
+        let node = &nodes[dir];
+        let parent = dirs.iter().position(|&d| d == node.parent).unwrap() as u16 + 1;
+        let (lba, parent) = if le {
+            (node.lba.to_le_bytes(), parent.to_le_bytes())
+        } else {
+            (node.lba.to_be_bytes(), parent.to_be_bytes())
+        };
+        table.extend([node.iso_name.len() as u8, 0]);
+        table.extend(lba);
+        table.extend(parent);
+        table.extend(&node.iso_name);
+        if node.iso_name.len() % 2 == 1 {
+            table.push(0);
+        }
+    }
+    table
+}
+
+/// Fills in the boot info table of the BIOS boot file, which tells it where
+/// it was loaded from.
+fn patch_boot_info_table(data: &mut Vec<u8>, lba: u32) -> anyhow::Result<()> {
+    if data.len() < BOOT_INFO_TABLE.end {
+        bail!("`{BIOS_BOOT_FILE}` is too short for a boot info table");
+    }
+    let len = data.len() as u32;
+    data.resize(data.len().next_multiple_of(4), 0);
+    let checksum = data[BOOT_INFO_TABLE.end..]
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .fold(0u32, u32::wrapping_add);
+    data.truncate(len as usize);
+
+    let table = &mut data[BOOT_INFO_TABLE];
+    table.fill(0);
+    table[0..4].copy_from_slice(&PRIMARY_VOLUME_DESCRIPTOR.to_le_bytes());
+    table[4..8].copy_from_slice(&lba.to_le_bytes());
+    table[8..12].copy_from_slice(&len.to_le_bytes());
+    table[12..16].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+/// Returns the El Torito boot catalog.
+fn boot_catalog(bios: &Node, uefi: &Node) -> Vec<u8> {
+    let mut catalog = vec![0u8; SECTOR_SIZE as usize];
+
+    // validation entry, platform x86.
+    catalog[0] = 1;
+    catalog[30] = 0x55;
+    catalog[31] = 0xAA;
+    let sum = catalog[..32]
+        .chunks(2)
+        .map(|word| u16::from_le_bytes([word[0], word[1]]))
+        .fold(0u16, u16::wrapping_add);
+    catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    // default entry: BIOS, without emulation.
+    catalog[32] = 0x88;
+    catalog[38..40].copy_from_slice(&BIOS_LOAD_SECTORS.to_le_bytes());
+    catalog[40..44].copy_from_slice(&bios.lba.to_le_bytes());
+
+    // the last section header, for UEFI, with one entry.
+    catalog[64] = 0x91;
+    catalog[65] = 0xEF;
+    catalog[66..68].copy_from_slice(&1u16.to_le_bytes());
+    catalog[96] = 0x88;
+    let count = u16::try_from(u64::from(uefi.size).div_ceil(512)).unwrap_or(u16::MAX);
+    catalog[102..104].copy_from_slice(&count.to_le_bytes());
+    catalog[104..108].copy_from_slice(&uefi.lba.to_le_bytes());
+    catalog
+}
+
+/// Returns an MBR with a partition covering the UEFI boot image.
+fn master_boot_record(uefi: &Node) -> Vec<u8> {
+    let mut mbr = vec![0u8; 512];
+    let entry = &mut mbr[446..462];
+    // addressed by LBA only.
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = 0xEF;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(uefi.lba * 4).to_le_bytes());
+    entry[12..16].copy_from_slice(&(uefi.size.div_ceil(512)).to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    mbr
+}
+
+/// Returns a descriptor field of `len` bytes holding `s`, padded with spaces.
+fn text(s: &str, len: usize) -> Vec<u8> {
+    let mut field = s.as_bytes().to_vec();
+    field.resize(len, b' ');
+    field
+}
+
+fn primary_volume_descriptor(
+    nodes: &[Node],
+    volume_sectors: u32,
+    path_table_len: u32,
+    path_table_sectors: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let mut d = vec![1];
+    d.extend(b"CD001\x01\x00");
+    d.extend(text("", 32)); // system
+    d.extend(text("NACL", 32)); // volume
+    d.extend([0; 8]);
+    d.extend(both32(volume_sectors));
+    d.extend([0; 32]);
+    d.extend(both16(1)); // volume set size
+    d.extend(both16(1)); // volume sequence number
+    d.extend(both16(SECTOR_SIZE as u16));
+    d.extend(both32(path_table_len));
+    d.extend(PATH_TABLES.to_le_bytes());
+    d.extend([0; 4]);
+    d.extend((PATH_TABLES + path_table_sectors).to_be_bytes());
+    d.extend([0; 4]);
+    d.extend(record(&[0], nodes[0].lba, nodes[0].size, true, &[])?);
+    d.extend(text("", 128)); // volume set
+    d.extend(text("", 128)); // publisher
+    d.extend(text("", 128)); // data preparer
+    d.extend(text("NACL_BOOT", 128)); // application
+    d.extend(text("", 37 * 3)); // copyright, abstract and bibliographic files
+    for _ in 0..4 {
+        // creation, modification, expiration and effective dates.
+        d.extend([b'0'; 16]);
+        d.push(0);
+    }
+    d.push(1); // file structure version
+    d.resize(SECTOR_SIZE as usize, 0);
+    Ok(d)
+}
+
+fn boot_record(catalog: u32) -> Vec<u8> {
+    let mut d = vec![0];
+    d.extend(b"CD001\x01");
+    let mut system = b"EL TORITO SPECIFICATION".to_vec();
+    system.resize(32, 0);
+    d.extend(system);
+    d.extend([0; 32]);
+    d.extend(catalog.to_le_bytes());
+    d.resize(SECTOR_SIZE as usize, 0);
+    d
+}
+
+fn write_at(file: &mut File, lba: u32, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(u64::from(lba) * SECTOR_SIZE))?;
+    file.write_all(data)
+}
+
+/// Creates a bootable ISO image at `out` holding the contents of `root`, which
+/// needs Limine's CD boot files at the top.
+pub fn create_iso(root: &Path, out: &Path) -> anyhow::Result<()> {
+    let (mut nodes, dirs) = read_tree(root)?;
+    let find = |nodes: &[Node], name: &str| {
+        nodes[0]
+            .children
+            .iter()
+            .copied()
+            .find(|&child| nodes[child].name == name && !nodes[child].is_dir())
+            .with_context(|| format!("`{name}` is missing from `{}`", root.display()))
+    };
+    let bios = find(&nodes, BIOS_BOOT_FILE)?;
+    let uefi = find(&nodes, UEFI_BOOT_IMAGE)?;
+
+    // the size of directories does not depend on where anything goes.
+    for &dir in &dirs {
+        nodes[dir].size = directory(&nodes, dir)?.len() as u32;
+    }
+    let path_table_len = path_table(&nodes, &dirs, true).len() as u32;
+    let path_table_sectors = sectors(u64::from(path_table_len));
+
+    let catalog = PATH_TABLES + 2 * path_table_sectors;
+    let mut next = catalog + 1;
+    for &dir in &dirs {
+        nodes[dir].lba = next;
+        next += sectors(u64::from(nodes[dir].size));
+    }
+    for node in nodes.iter_mut().filter(|node| !node.is_dir()) {
+        node.lba = next;
+        next += sectors(u64::from(node.size));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out)?;
+    file.set_len(u64::from(next) * SECTOR_SIZE)?;
+
+    write_at(&mut file, 0, &master_boot_record(&nodes[uefi]))?;
+    write_at(
+        &mut file,
+        PRIMARY_VOLUME_DESCRIPTOR,
+        &primary_volume_descriptor(&nodes, next, path_table_len, path_table_sectors)?,
+    )?;
+    write_at(&mut file, BOOT_RECORD, &boot_record(catalog))?;
+    let mut terminator = vec![255];
+    terminator.extend(b"CD001\x01");
+    write_at(&mut file, TERMINATOR, &terminator)?;
+    write_at(&mut file, PATH_TABLES, &path_table(&nodes, &dirs, true))?;
+    write_at(
+        &mut file,
+        PATH_TABLES + path_table_sectors,
+        &path_table(&nodes, &dirs, false),
+    )?;
+    write_at(
+        &mut file,
+        catalog,
+        &boot_catalog(&nodes[bios], &nodes[uefi]),
+    )?;
+
+    for &dir in &dirs {
+        write_at(&mut file, nodes[dir].lba, &directory(&nodes, dir)?)?;
+    }
+    for (index, node) in nodes.iter().enumerate() {
+        let Some(source) = &node.source else { continue };
+        if index == bios {
+            let mut data = fs::read(source)?;
+            patch_boot_info_table(&mut data, node.lba)?;
+            write_at(&mut file, node.lba, &data)?;
+        } else {
+            file.seek(SeekFrom::Start(u64::from(node.lba) * SECTOR_SIZE))?;
+            io::copy(&mut File::open(source)?, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn u16_le(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_le(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    /// A directory under the temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("nacl_boot-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn iso_names() {
+        assert_eq!(iso_name("limine.conf", false), b"LIMINE.CONF;1");
+        assert_eq!(iso_name("nacl", false), b"NACL.;1");
+        assert_eq!(iso_name("EFI", true), b"EFI");
+        assert_eq!(iso_name("my-file.tar.gz", false), b"MY_FILE_TAR.GZ;1");
+        assert_eq!(iso_name("a.verylongextension", false), b"A.VERYLONG;1");
+        let long = "x".repeat(40);
+        assert_eq!(iso_name(&long, true).len(), 31);
+        // 30 characters for the name and extension, besides `.` and `;1`.
+        assert_eq!(iso_name(&format!("{long}.bin"), false).len(), 33);
+    }
+
+    #[test]
+    fn record_padding() {
+        // the identifier is padded to an even length.
+        let even = record(b"AB", 20, 4096, true, &[]).unwrap();
+        assert_eq!(even.len(), 36);
+        assert_eq!(even[0], 36);
+        assert_eq!(even[32], 2);
+        assert_eq!(even[35], 0);
+        let odd = record(b"ABC", 20, 4096, false, &[]).unwrap();
+        assert_eq!(odd.len(), 36);
+        // and the whole record too.
+        let system_use = record(b"ABC", 20, 4096, false, b"X").unwrap();
+        assert_eq!(system_use.len(), 38);
+        assert_eq!(system_use[36], b'X');
+        assert_eq!(system_use[37], 0);
+
+        assert_eq!(u32_le(&even, 2), 20);
+        assert_eq!(&even[6..10], &20u32.to_be_bytes());
+        assert_eq!(u32_le(&even, 10), 4096);
+        assert_eq!(even[25], 2);
+        assert_eq!(odd[25], 0);
+    }
+
+    #[test]
+    fn record_length() {
+        let name = "n".repeat(MAX_NAME_LEN);
+        let identifier = iso_name(&name, false);
+        let mut system_use = posix_entry(false);
+        system_use.extend(name_entry(&name));
+        let longest = record(&identifier, 0, 0, false, &system_use).unwrap();
+        assert!(longest.len() <= MAX_RECORD_LEN);
+        assert_eq!(usize::from(longest[0]), longest.len());
+
+        system_use.extend(b"NM");
+        assert!(record(&identifier, 0, 0, false, &system_use).is_err());
+    }
+
+    #[test]
+    fn boot_catalog_checksum() {
+        let node = |lba, size| Node {
+            name: String::new(),
+            iso_name: Vec::new(),
+            parent: 0,
+            source: Some(PathBuf::new()),
+            children: Vec::new(),
+            lba,
+            size,
+        };
+        let catalog = boot_catalog(&node(30, 2048), &node(31, 1440 * 1024));
+        let sum = (0..32)
+            .step_by(2)
+            .map(|at| u16_le(&catalog, at))
+            .fold(0u16, u16::wrapping_add);
+        assert_eq!(sum, 0);
+        assert_eq!(&catalog[30..32], &[0x55, 0xAA]);
+        assert_eq!(u16_le(&catalog, 38), BIOS_LOAD_SECTORS);
+        assert_eq!(u32_le(&catalog, 40), 30);
+        assert_eq!(u16_le(&catalog, 102), 2880);
+        assert_eq!(u32_le(&catalog, 104), 31);
+    }
+
+    #[test]
+    fn boot_info_table() {
+        let mut data: Vec<u8> = (0..70).collect();
+        patch_boot_info_table(&mut data, 40).unwrap();
+        assert_eq!(data.len(), 70);
+        assert_eq!(u32_le(&data, 8), PRIMARY_VOLUME_DESCRIPTOR);
+        assert_eq!(u32_le(&data, 12), 40);
+        assert_eq!(u32_le(&data, 16), 70);
+        // the words after the table, the last one padded with zeros.
+        let checksum = u32::from_le_bytes([64, 65, 66, 67]) + u32::from_le_bytes([68, 69, 0, 0]);
+        assert_eq!(u32_le(&data, 20), checksum);
+        assert!(data[24..64].iter().all(|&b| b == 0));
+        assert_eq!(&data[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        assert!(patch_boot_info_table(&mut vec![0; 63], 40).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("iso");
+        let root = dir.0.join("root");
+        let bios: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let uefi = vec![0xAB; 5000];
+        fs::create_dir_all(root.join("boot")).unwrap();
+        fs::write(root.join(BIOS_BOOT_FILE), &bios).unwrap();
+        fs::write(root.join(UEFI_BOOT_IMAGE), &uefi).unwrap();
+        fs::write(root.join("boot/nacl"), b"kernel").unwrap();
+        let out = dir.0.join("nacl.iso");
+        create_iso(&root, &out).unwrap();
+
+        let image = fs::read(&out).unwrap();
+        assert_eq!(image.len() % SECTOR_SIZE as usize, 0);
+        let sector =
+            |lba: u32| &image[lba as usize * SECTOR_SIZE as usize..][..SECTOR_SIZE as usize];
+
+        let pvd = sector(PRIMARY_VOLUME_DESCRIPTOR);
+        assert_eq!(&pvd[..7], b"\x01CD001\x01");
+        assert_eq!(u32_le(pvd, 80) as usize * SECTOR_SIZE as usize, image.len());
+        assert_eq!(u32_le(pvd, 140), PATH_TABLES);
+        let root_lba = u32_le(&pvd[156..], 2);
+        // the `.` record of the root points to itself.
+        assert_eq!(u32_le(sector(root_lba), 2), root_lba);
+
+        let boot_record = sector(BOOT_RECORD);
+        assert_eq!(&boot_record[..7], b"\x00CD001\x01");
+        assert_eq!(&boot_record[7..30], b"EL TORITO SPECIFICATION");
+        assert_eq!(&sector(TERMINATOR)[..7], b"\xffCD001\x01");
+
+        let catalog = sector(u32_le(boot_record, 71));
+        assert_eq!(&catalog[30..32], &[0x55, 0xAA]);
+        let bios_lba = u32_le(catalog, 40);
+        let uefi_lba = u32_le(catalog, 104);
+        let bios_data = &sector(bios_lba)[..bios.len().min(SECTOR_SIZE as usize)];
+        assert_eq!(u32_le(bios_data, 12), bios_lba);
+        assert_eq!(&bios_data[64..], &bios[64..SECTOR_SIZE as usize]);
+        assert_eq!(
+            &image[uefi_lba as usize * SECTOR_SIZE as usize..][..uefi.len()],
+            &uefi[..]
+        );
+
+        // the MBR partition covers the UEFI boot image, in 512 byte sectors.
+        assert_eq!(&image[510..512], &[0x55, 0xAA]);
+        assert_eq!(image[446 + 4], 0xEF);
+        assert_eq!(u32_le(&image, 446 + 8), uefi_lba * 4);
+    }
+
+    #[test]
+    fn long_names() {
+        let dir = TempDir::new("long-names");
+        fs::write(dir.0.join("n".repeat(MAX_NAME_LEN + 1)), b"").unwrap();
+        assert!(create_iso(&dir.0, &dir.0.join("nacl.iso")).is_err());
+    }
+}
@@ -1,78 +1,56 @@
-#![feature(exit_status_error, iter_advance_by)]
+#![feature(exit_status_error)]
 
 use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::{env, fs, io};
+use std::{fs, io};
 
 use anyhow::Context;
 
-const RUN_ARGS: &[&str] = &["--no-reboot", "-s"];
+mod cargo;
+mod cli;
+mod image;
+mod iso;
+mod qemu;
+mod test;
 
-const REGEX: &str = "\"executable\":\".+?\"";
-const REGEX_HDR: &str = "\"executable\":\"";
+use cli::{Options, Subcommand};
 
 fn main() -> anyhow::Result<()> {
-    let mut args = std::env::args().skip(1); // skip executable name
-    let cargo = env::var("CARGO")?;
-    let output = Command::new(cargo)
-        .arg("build")
-        .arg("--release")
-        .arg("--target")
-        .arg("x86_64-unknown-none")
-        .arg("--message-format=json")
-        .env("RUSTFLAGS", "-C relocation-model=static")
-        .current_dir(Path::new("./nacl").canonicalize()?)
-        .output()?;
-    output.status.exit_ok()?;
-    let re = regex::Regex::new(REGEX)?;
-    let output = String::from_utf8_lossy(&output.stdout);
-    let mut match_ = re.find(&output).context("no executable generated")?.range();
-    match_
-        .advance_by(REGEX_HDR.len())
-        .map_err(|_| anyhow::anyhow!("regex match"))?;
-    match_
-        .advance_back_by(1)
-        .map_err(|_| anyhow::anyhow!("regex match"))?;
-    let kernel_binary_path = Path::new(&output[match_]);
-    eprintln!("kernel_binary: {kernel_binary_path:?}");
-
-    let no_boot = if let Some(arg) = args.next() {
-        match arg.as_str() {
-            "--no-run" => true,
-            other => panic!("unexpected argument `{}`", other),
-        }
-    } else {
-        false
+    let Some(options) = Options::parse(std::env::args().skip(1))? else {
+        println!("{}", cli::USAGE);
+        return Ok(());
     };
 
-    create_disk_images(kernel_binary_path)?;
-    println!("bios created");
-    /*Command::new("/usr/bin/env")
-        .arg("sh")
-        .arg("./limine/install-sh")
-        .arg(&bios)
-        .status()?
-        .exit_ok()?;*/
+    if options.command == Subcommand::Test {
+        return test::run(&options);
+    }
+
+    let kernel_binary_path = cargo::build_kernel(false, options.release)?;
+    eprintln!("kernel_binary: {kernel_binary_path:?}");
+    if options.command == Subcommand::Build {
+        return Ok(());
+    }
 
-    if no_boot {
-        // println!("Created disk image at `{}`", bios.display());
+    let root = Path::new("./iso_root");
+    let image = create_disk_images(
+        &kernel_binary_path,
+        root,
+        options.cmdline.as_deref(),
+        options.iso,
+    )?;
+    println!("created disk image at `{}`", image.display());
+    if options.command == Subcommand::Image {
         return Ok(());
     }
 
-    let mut run_cmd = Command::new("qemu-system-x86_64-uefi");
-    run_cmd
-        .arg("-enable-kvm")
-        .arg("-drive")
-        .arg(format!("format=raw,file=fat:rw:iso_root"))
-        .arg("-serial")
-        .arg("stdio")
-        // .arg("-smp")
-        // .arg("4")
-        .arg("-m")
-        .arg("1G");
-    run_cmd.args(RUN_ARGS);
-    run_cmd
+    if options.command == Subcommand::Gdb {
+        eprintln!(
+            "waiting for gdb: gdb {} -ex 'target remote localhost:1234'",
+            kernel_binary_path.display()
+        );
+    }
+    qemu::command(&options, &image)?
         .status()
         .context("running qemu")?
         .exit_ok()
@@ -81,8 +59,17 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn create_disk_images(kernel_binary_path: &Path) -> anyhow::Result<()> {
-    fs::create_dir_all("./iso_root/")?;
+/// Puts Limine, its configuration and the kernel into `root`, and makes a disk
+/// image of them next to the kernel, or an ISO image if `iso` is set. The
+/// kernel is always called `nacl`, which is where `limine.conf` looks for it,
+/// and gets `cmdline` as its command line. Returns the path of the image.
+pub fn create_disk_images(
+    kernel_binary_path: &Path,
+    root: &Path,
+    cmdline: Option<&str>,
+    iso: bool,
+) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(root)?;
     for (file, dest) in [
         ("./limine/BOOTX64.EFI", "EFI/BOOT"),
         ("./limine/limine-uefi-cd.bin", ""),
@@ -93,44 +80,34 @@ pub fn create_disk_images(kernel_binary_path: &Path) -> anyhow::Result<()> {
     .iter()
     .copied()
     .map(|(x, y)| (Path::new(x), y))
-    .chain([(kernel_binary_path, "")])
     .map(|(x, y)| (x.canonicalize(), y))
     {
         let file = file?;
-        let dest = if dest.is_empty() {
-            PathBuf::from(format!(
-                "./iso_root/{}",
-                file.file_name().unwrap().to_str().unwrap()
-            )).to_owned()
-        } else {
-            Path::new("./iso_root").join(dest).join(file.file_name().unwrap().to_str().unwrap())
-        };
+        let dest = root.join(dest).join(file.file_name().unwrap());
         fs::create_dir_all(dest.parent().unwrap())?;
         io::copy(
             &mut File::open(&file)?,
             &mut File::create(dest)?,
         )?;
     }
-    /*let image_path = kernel_binary_path.with_extension("iso");
-    Command::new("xorriso")
-        .arg("-as")
-        .arg("mkisofs")
-        .arg("-b")
-        .arg("limine-bios-cd.bin")
-        .arg("-no-emul-boot")
-        .arg("-boot-load-size")
-        .arg("4")
-        .arg("-boot-info-table")
-        .arg("--efi-boot")
-        .arg("limine-uefi-cd.bin")
-        .arg("-efi-boot-part")
-        .arg("--efi-boot-image")
-        .arg("--protective-msdos-label")
-        .arg("-o")
-        .arg(&image_path)
-        .arg("./iso_root")
-        .status()?
-        .exit_ok()?;
-    Ok(image_path)*/
-    Ok(())
+    io::copy(
+        &mut File::open(kernel_binary_path)?,
+        &mut File::create(root.join("nacl"))?,
+    )?;
+    if let Some(cmdline) = cmdline {
+        let mut conf = fs::OpenOptions::new()
+            .append(true)
+            .open(root.join("limine.conf"))?;
+        writeln!(conf, "\n    cmdline: {cmdline}")?;
+    }
+    let image = if iso {
+        let image = kernel_binary_path.with_extension("iso");
+        iso::create_iso(root, &image)?;
+        image
+    } else {
+        let image = kernel_binary_path.with_extension("img");
+        image::create_disk_image(root, &image)?;
+        image
+    };
+    Ok(image)
 }
@@ -0,0 +1,98 @@
+//! Putting together the QEMU command line.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+
+use crate::cli::{Accel, Options, Subcommand};
+
+/// The firmware bundled with the repository, then where distributions install
+/// the OVMF firmware.
+const FIRMWARE_PATHS: &[&str] = &[
+    "./OVMF-pure-efi.fd",
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    "/usr/share/ovmf/OVMF.fd",
+    "/usr/share/ovmf/x64/OVMF_CODE.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    "/usr/share/qemu/edk2-x86_64-code.fd",
+];
+
+/// Whether QEMU can use KVM on this machine.
+pub fn kvm_available() -> bool {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok()
+}
+
+fn find_firmware() -> Option<PathBuf> {
+    FIRMWARE_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+/// Returns the QEMU command booting the disk image at `image` as `options` say.
+pub fn command(options: &Options, image: &Path) -> anyhow::Result<Command> {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    // QEMU boots with SeaBIOS unless told otherwise.
+    if !options.bios {
+        let firmware = match &options.firmware {
+            Some(path) if !path.is_file() => bail!("no firmware at `{}`", path.display()),
+            Some(path) => path.clone(),
+            None => find_firmware().ok_or_else(|| {
+                anyhow!("no UEFI firmware found, pass one with `--firmware <PATH>`")
+            })?,
+        };
+        cmd.arg("-drive").arg(format!(
+            "if=pflash,format=raw,readonly=on,file={}",
+            firmware.display()
+        ));
+    }
+
+    let kvm = match options.accel {
+        Accel::Auto => kvm_available(),
+        Accel::Kvm => true,
+        Accel::Tcg => false,
+    };
+    cmd.arg("-accel").arg(if kvm { "kvm" } else { "tcg" });
+
+    if options.iso {
+        cmd.arg("-cdrom").arg(image);
+    } else {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={}", image.display()));
+    }
+    cmd.arg("-serial")
+        .arg("stdio")
+        .arg("-smp")
+        .arg(options.smp.to_string())
+        .arg("-m")
+        .arg(&options.memory)
+        .arg("--no-reboot");
+
+    match options.command {
+        Subcommand::Run => {
+            cmd.arg("-s");
+        }
+        Subcommand::Gdb => {
+            cmd.arg("-s").arg("-S");
+        }
+        Subcommand::Test => {
+            cmd.arg("-display")
+                .arg("none")
+                .arg("-device")
+                .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+        }
+        Subcommand::Build | Subcommand::Image => {}
+    }
+
+    cmd.args(&options.qemu_args);
+    Ok(cmd)
+}
@@ -0,0 +1,214 @@
+//! `nacl_boot test`: runs the kernel test suite in QEMU without a display.
+//!
+//! The test kernel reports every test on the serial port, ends with a
+//! `test result:` line and exits QEMU through the `isa-debug-exit` device,
+//! which turns the code the kernel writes into QEMU's exit status.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+use std::{io, thread};
+
+use anyhow::{bail, Context};
+
+use crate::cli::Options;
+use crate::{cargo, qemu};
+
+/// QEMU's exit status when the kernel exits with `QemuExitCode::Success`.
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+/// QEMU's exit status when the kernel exits with `QemuExitCode::Failed`.
+const QEMU_FAILED: i32 = (0x11 << 1) | 1;
+
+/// What the kernel reported on the serial port so far.
+#[derive(Debug, Default)]
+struct Report {
+    passed: usize,
+    /// names of the failed tests, with the reason when it was printed.
+    failed: Vec<(String, Option<String>)>,
+    /// the `test result:` line, once it was printed.
+    result: Option<String>,
+    /// whether the last line was a failure, whose reason may follow.
+    after_failure: bool,
+}
+
+impl Report {
+    fn parse_line(&mut self, line: &str) {
+        let after_failure = std::mem::take(&mut self.after_failure);
+        if let Some(result) = line.strip_prefix("test result: ") {
+            self.result = Some(result.to_owned());
+        } else if let Some(test) = line.strip_prefix("test ") {
+            if test.ends_with(" ... ok") {
+                self.passed += 1;
+            } else if let Some(name) = test.strip_suffix(" ... FAILED") {
+                self.failed.push((name.to_owned(), None));
+                self.after_failure = true;
+            }
+        } else if let Some(reason) = line.strip_prefix("    ") {
+            // the reason of a failure follows on the next line.
+            if let (true, Some((_, slot))) = (after_failure, self.failed.last_mut()) {
+                *slot = Some(reason.to_owned());
+            }
+        }
+    }
+}
+
+/// Builds the test kernel, boots it and checks the results. Returns an error
+/// if a test failed, the kernel did not finish in time or QEMU failed.
+pub fn run(options: &Options) -> anyhow::Result<()> {
+    let timeout = options.timeout;
+    let kernel_binary_path = cargo::build_kernel(true, options.release)?;
+    eprintln!("kernel_binary: {kernel_binary_path:?}");
+
+    let root = Path::new("./target/iso_root_test");
+    let image = crate::create_disk_images(
+        &kernel_binary_path,
+        root,
+        options.cmdline.as_deref(),
+        options.iso,
+    )?;
+
+    let mut run_cmd = qemu::command(options, &image)?;
+    run_cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+    let mut qemu = run_cmd.spawn().context("running qemu")?;
+
+    let stdout = qemu.stdout.take().context("qemu stdout")?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        let mut line = Vec::new();
+        // the firmware may print anything, so don't insist on UTF-8.
+        while matches!(stdout.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+            let text = String::from_utf8_lossy(&line);
+            if tx.send(text.trim_end().to_owned()).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut report = Report::default();
+    let mut out = io::stdout().lock();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => {
+                writeln!(out, "{line}")?;
+                report.parse_line(&line);
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = qemu.kill();
+                let _ = qemu.wait();
+                bail!("tests did not finish within {} seconds", timeout.as_secs());
+            }
+        }
+    }
+    drop(out);
+
+    let status = qemu.wait().context("waiting for qemu")?;
+
+    if !report.failed.is_empty() {
+        eprintln!("\nfailures:");
+        for (name, reason) in &report.failed {
+            match reason {
+                Some(reason) => eprintln!("    {name}: {reason}"),
+                None => eprintln!("    {name}"),
+            }
+        }
+    }
+
+    match (status.code(), &report.result) {
+        (Some(QEMU_SUCCESS), Some(_)) if report.failed.is_empty() => {
+            eprintln!("{} tests passed", report.passed);
+            Ok(())
+        }
+        (Some(QEMU_SUCCESS | QEMU_FAILED), Some(result)) => {
+            bail!("test result: {result}")
+        }
+        (Some(QEMU_SUCCESS | QEMU_FAILED), None) => {
+            bail!("the kernel exited without reporting a test result")
+        }
+        (_, Some(_)) => bail!("qemu exited with {status}"),
+        (_, None) => bail!("qemu exited with {status} before the tests finished"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Report;
+
+    fn report(output: &str) -> Report {
+        let mut report = Report::default();
+        for line in output.lines() {
+            report.parse_line(line);
+        }
+        report
+    }
+
+    #[test]
+    fn passed() {
+        let report = report(
+            "running 2 tests\n\
+             test nacl::testing::tests::passes ... ok\n\
+             test nacl::task::map::tests::insert ... ok\n\
+             test result: ok. 2 passed; 0 failed",
+        );
+        assert_eq!(report.passed, 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.result.as_deref(), Some("ok. 2 passed; 0 failed"));
+    }
+
+    #[test]
+    fn failed() {
+        let report = report(
+            "running 3 tests\n\
+             test nacl::a ... FAILED\n\
+             \x20   panicked: assertion failed: false\n\
+             test nacl::b ... FAILED\n\
+             test nacl::c ... ok\n\
+             \x20   not a reason\n\
+             test result: FAILED. 1 passed; 2 failed",
+        );
+        assert_eq!(report.passed, 1);
+        assert_eq!(
+            report.failed,
+            [
+                (
+                    "nacl::a".to_owned(),
+                    Some("panicked: assertion failed: false".to_owned())
+                ),
+                ("nacl::b".to_owned(), None),
+            ]
+        );
+        assert_eq!(report.result.as_deref(), Some("FAILED. 1 passed; 2 failed"));
+    }
+
+    #[test]
+    fn firmware_noise() {
+        let report = report(
+            "\x1b[2J\x1b[01;01H\x1b[=3h\x1b[2J\x1b[01;01HBdsDxe: loading Boot0001 \"UEFI QEMU DVD-ROM\"\n\
+             BdsDxe: starting Boot0001\n\
+             \x20   test nacl::indented ... ok\n\
+             testing the firmware ... FAILED\n\
+             running 1 tests\n\
+             test nacl::testing::tests::passes ... ok",
+        );
+        assert_eq!(report.passed, 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.result, None);
+    }
+
+    #[test]
+    fn uncaught_panic() {
+        let report = report(
+            "running 2 tests\n\
+             test nacl::testing::tests::passes ... ok\n\
+             panicked at nacl/src/main.rs:10:5:\n\
+             test result: FAILED. uncaught panic",
+        );
+        assert_eq!(report.passed, 1);
+        assert_eq!(report.result.as_deref(), Some("FAILED. uncaught panic"));
+    }
+}
@@ -0,0 +1,151 @@
+//! A text console on a grid of characters.
+//!
+//! The console keeps the characters on the screen and where the next one goes.
+//! Text is written on the last line, and every new line scrolls the grid up by
+//! one line. Drawing the characters is left to a [`FrameBufferSink`].
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::fmt;
+
+/// Foreground color of the console, as `0xRRGGBB`.
+pub const FOREGROUND: u32 = 0xFFFFFF;
+/// Background color of the console.
+pub const BACKGROUND: u32 = 0;
+
+/// Something characters can be drawn on, like a framebuffer.
+pub trait FrameBufferSink {
+    /// Number of characters that fit on a line.
+    fn columns(&self) -> usize;
+
+    /// Number of lines that fit on the screen.
+    fn rows(&self) -> usize;
+
+    /// Draws `c` at `column` and `row`, with the colors `fg` and `bg` as
+    /// `0xRRGGBB`.
+    fn draw_char(&mut self, c: char, column: usize, row: usize, fg: u32, bg: u32);
+}
+
+pub struct Console<S> {
+    sink: S,
+    /// the characters on the screen, line by line.
+    chars: Box<[char]>,
+    columns: usize,
+    /// the column of the next character on the last line.
+    idx: usize,
+}
+
+impl<S: fmt::Debug> fmt::Debug for Console<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Console")
+            .field("sink", &self.sink)
+            .field("columns", &self.columns)
+            .field("rows", &self.rows())
+            .field("idx", &self.idx)
+            .finish()
+    }
+}
+
+impl<S: FrameBufferSink> Console<S> {
+    /// Creates an empty console covering all of `sink`.
+    pub fn new(sink: S) -> Self {
+        let columns = sink.columns();
+        let rows = sink.rows();
+        assert!(columns > 0 && rows > 0, "screen too small for a console");
+        Console {
+            sink,
+            chars: vec![' '; columns * rows].into_boxed_slice(),
+            columns,
+            idx: 0,
+        }
+    }
+}
+
+impl<S> Console<S> {
+    #[inline]
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    #[inline]
+    pub fn rows(&self) -> usize {
+        self.chars.len() / self.columns
+    }
+
+    /// Returns the characters of line `row`, counted from the top.
+    pub fn line(&self, row: usize) -> &[char] {
+        &self.chars[row * self.columns..][..self.columns]
+    }
+
+    #[inline]
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    #[inline]
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S: FrameBufferSink> Console<S> {
+    pub fn put(&mut self, c: char) {
+        if c == '\n' {
+            self.idx = 0;
+            self.newline();
+            self.redraw();
+            return;
+        }
+
+        let last_line = self.chars.len() - self.columns;
+
+        if self.idx == self.columns {
+            // content wraps to the next line
+            self.idx = 0;
+            self.newline();
+            self.chars[last_line] = c;
+            self.redraw()
+        } else {
+            self.chars[last_line + self.idx] = c;
+            let row = self.rows() - 1;
+            self.sink
+                .draw_char(c, self.idx, row, FOREGROUND, BACKGROUND);
+        }
+
+        self.idx += 1;
+    }
+
+    /// Scrolls the grid up by one line, without drawing it.
+    pub fn newline(&mut self) {
+        self.chars.rotate_left(self.columns);
+        let len = self.chars.len();
+        self.chars[len - self.columns..].fill(' ');
+    }
+
+    /// Redraws the whole grid.
+    pub fn redraw(&mut self) {
+        for (i, &c) in self.chars.iter().enumerate() {
+            self.sink.draw_char(
+                c,
+                i % self.columns,
+                i / self.columns,
+                FOREGROUND,
+                BACKGROUND,
+            );
+        }
+    }
+}
+
+impl<S: FrameBufferSink> fmt::Write for Console<S> {
+    fn write_char(&mut self, c: char) -> fmt::Result {
+        self.put(c);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.put(c)
+        }
+        Ok(())
+    }
+}
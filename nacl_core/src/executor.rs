@@ -0,0 +1,271 @@
+//! The scheduling logic of the executor.
+//!
+//! Every processor keeps one ready queue per [`Priority`]. Tasks are normally
+//! taken from the most important non-empty queue, but every priority also has a
+//! maximum wait time, measured in ticks of the [`TimerSource`]. A queue that
+//! has not been served for longer than that goes first, so a busy queue cannot
+//! starve the ones below.
+//!
+//! Tasks themselves live in [`Slot`]s, which any processor may poll. A slot
+//! makes sure only one processor polls its task at a time, and that a wake that
+//! arrives during a poll is not lost.
+
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::num::Wrapping;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use nacl_deque::{Epoch, Stealer, Worker};
+
+/// Tells which processor the caller runs on.
+pub trait CpuId {
+    /// The number of the current processor, `None` if it is not known yet.
+    fn current() -> Option<u32>;
+}
+
+/// A timer ticking on the current processor.
+pub trait TimerSource {
+    /// The number of ticks so far, which wraps around.
+    fn now() -> Wrapping<usize>;
+}
+
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Work deferred by interrupt handlers, which should run as soon as possible.
+    BottomHalf,
+    /// Tasks someone is waiting on, like the console.
+    Interactive,
+    #[default]
+    Normal,
+    /// Bulk work that runs when nothing else needs the processor.
+    Background,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+
+    /// All priorities, most important first.
+    pub const ALL: [Priority; Priority::COUNT] = [
+        Priority::BottomHalf,
+        Priority::Interactive,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    /// Number of timer ticks a ready task of this priority may wait before it
+    /// runs ahead of more important tasks.
+    pub fn max_wait(self) -> usize {
+        match self {
+            Priority::BottomHalf => 0,
+            Priority::Interactive => 2,
+            Priority::Normal => 10,
+            Priority::Background => 50,
+        }
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The ready queues of one processor, holding tasks of type `T`. `E` is the
+/// epoch backend of the queues, and `C` the timer the waits are measured with.
+///
+/// Tasks pinned to this processor are kept apart from the others, in queues
+/// that are never handed out to stealers.
+pub struct RunQueues<T, E, C> {
+    queues: [Worker<T, E>; Priority::COUNT],
+    pinned: [Worker<T, E>; Priority::COUNT],
+    /// the tick at which each queue was last served or seen empty.
+    served: [Cell<Wrapping<usize>>; Priority::COUNT],
+    _timer: PhantomData<fn() -> C>,
+}
+
+impl<T, E: Epoch, C: TimerSource> Default for RunQueues<T, E, C> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, E: Epoch, C: TimerSource> RunQueues<T, E, C> {
+    pub fn new() -> Self {
+        RunQueues {
+            queues: Priority::ALL.map(|_| Worker::new_fifo()),
+            pinned: Priority::ALL.map(|_| Worker::new_fifo()),
+            served: Priority::ALL.map(|_| Cell::new(Wrapping(0))),
+            _timer: PhantomData,
+        }
+    }
+
+    /// Whether no task of the priority at `index` is ready.
+    fn level_is_empty(&self, index: usize) -> bool {
+        self.pinned[index].is_empty() && self.queues[index].is_empty()
+    }
+
+    fn push_to(&self, queue: &Worker<T, E>, task: T, priority: Priority) {
+        if self.level_is_empty(priority.index()) {
+            // the wait starts now, not when the queue was last served.
+            self.served[priority.index()].set(C::now());
+        }
+        queue.push(task);
+    }
+
+    /// Queues `task` at `priority`, where other processors may steal it.
+    pub fn push(&self, task: T, priority: Priority) {
+        self.push_to(&self.queues[priority.index()], task, priority);
+    }
+
+    /// Queues `task` at `priority`, where no other processor can take it.
+    pub fn push_pinned(&self, task: T, priority: Priority) {
+        self.push_to(&self.pinned[priority.index()], task, priority);
+    }
+
+    /// Takes the next task to run.
+    pub fn pop(&self) -> Option<T> {
+        let now = C::now();
+        let mut next = None;
+        let mut most_overdue = 0;
+        for priority in Priority::ALL {
+            let index = priority.index();
+            if self.level_is_empty(index) {
+                self.served[index].set(now);
+                continue;
+            }
+
+            let waited = (now - self.served[index].get()).0;
+            let overdue = waited.saturating_sub(priority.max_wait());
+            if next.is_none() || overdue > most_overdue {
+                next = Some(priority);
+                most_overdue = overdue;
+            }
+        }
+
+        let index = next?.index();
+        self.served[index].set(now);
+        self.pinned[index]
+            .pop()
+            .or_else(|| self.queues[index].pop())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..Priority::COUNT).all(|index| self.level_is_empty(index))
+    }
+
+    pub fn stealers(&self) -> RunQueueStealers<T, E, C> {
+        RunQueueStealers(self.queues.each_ref().map(Worker::stealer), PhantomData)
+    }
+}
+
+/// Steals tasks from the [`RunQueues`] of another processor.
+pub struct RunQueueStealers<T, E, C>([Stealer<T, E>; Priority::COUNT], PhantomData<fn() -> C>);
+
+impl<T, E: Epoch, C: TimerSource> RunQueueStealers<T, E, C> {
+    /// Moves a batch of the most important unpinned tasks into `dest`, keeping
+    /// their priority. Returns whether anything was stolen.
+    pub fn steal_into(&self, dest: &RunQueues<T, E, C>) -> bool {
+        Priority::ALL.into_iter().any(|priority| {
+            let index = priority.index();
+            let was_empty = dest.level_is_empty(index);
+            let mut res = self.0[index].steal_batch(&dest.queues[index]);
+            while res.is_retry() {
+                res = self.0[index].steal_batch(&dest.queues[index]);
+            }
+            if res.is_success() && was_empty {
+                dest.served[index].set(C::now());
+            }
+            res.is_success()
+        })
+    }
+}
+
+/// Holds a task of type `T` that may be polled by any processor, but by only
+/// one at a time. `C` tells the processors apart.
+///
+/// A processor that wants to poll the task [acquires](Slot::try_acquire) it,
+/// and [releases](Slot::release) it after the poll. If the task is woken and
+/// popped elsewhere in the meantime, the processor that releases it is told to
+/// queue it again.
+pub struct Slot<T, C> {
+    /// the processor the task was spawned on.
+    home: u32,
+    /// `None` once the task finished.
+    task: UnsafeCell<Option<T>>,
+    /// set while a processor polls the task, and so owns `task`.
+    polling: AtomicBool,
+    /// set when the task was popped while it was being polled elsewhere.
+    repoll: AtomicBool,
+    _cpu: PhantomData<fn() -> C>,
+}
+
+// SAFETY: `task` is only accessed by the processor that set `polling`.
+unsafe impl<T: Send, C> Sync for Slot<T, C> {}
+
+impl<T, C: CpuId> Slot<T, C> {
+    /// Creates a slot for `task`, spawned on the current processor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current processor is not known.
+    pub fn new(task: T) -> Self {
+        Slot {
+            home: C::current().expect("task spawned on an unknown processor"),
+            task: UnsafeCell::new(Some(task)),
+            polling: AtomicBool::new(false),
+            repoll: AtomicBool::new(false),
+            _cpu: PhantomData,
+        }
+    }
+
+    /// The processor the task was spawned on.
+    #[inline]
+    pub fn home(&self) -> u32 {
+        self.home
+    }
+
+    /// Whether this runs on the processor the task was spawned on.
+    #[inline]
+    pub fn is_home(&self) -> bool {
+        C::current() == Some(self.home)
+    }
+
+    /// Takes ownership of the task, or makes the processor that owns it queue
+    /// it again once it is done.
+    pub fn try_acquire(&self) -> bool {
+        if !self.polling.swap(true, Ordering::Acquire) {
+            return true;
+        }
+        self.repoll.store(true, Ordering::Release);
+        // the owner may have released the task before `repoll` was set.
+        !self.polling.swap(true, Ordering::Acquire)
+    }
+
+    /// Gives up ownership of the task. Returns whether the task has to be
+    /// queued again, because it was popped while it was owned.
+    #[must_use]
+    pub fn release(&self) -> bool {
+        self.polling.store(false, Ordering::Release);
+        self.repoll.swap(false, Ordering::AcqRel)
+    }
+
+    /// Returns the task, `None` if it finished.
+    ///
+    /// # Safety
+    ///
+    /// The task must be acquired, and the reference must not outlive the
+    /// ownership.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn task(&self) -> &mut Option<T> {
+        &mut *self.task.get()
+    }
+
+    /// Takes the task out of the slot.
+    ///
+    /// # Safety
+    ///
+    /// The task must be acquired.
+    pub unsafe fn take(&self) -> Option<T> {
+        self.task().take()
+    }
+}
@@ -0,0 +1,201 @@
+//! PC Screen Font (PSF2) parsing.
+//!
+//! A PSF2 file is a header, followed by the bitmaps of the glyphs and an
+//! optional unicode table. Each glyph is `height` rows of `width` bits, every
+//! row padded to whole bytes, with the most significant bit on the left. The
+//! unicode table lists the UTF-8 characters of each glyph in glyph order, each
+//! list ended by `0xFF`. Sequences of combining characters, which start with
+//! `0xFE`, are ignored.
+
+use core::mem::size_of;
+use core::{fmt, ptr, str};
+
+use hashbrown::HashMap;
+
+const PSF2_MAGIC: u32 = 0x864A_B572;
+/// Set in `flags` if the font has a unicode table.
+const HAS_UNICODE_TABLE: u32 = 1;
+/// Ends the characters of a glyph in the unicode table.
+const SEPARATOR: u8 = 0xFF;
+/// Starts a sequence of combining characters in the unicode table.
+const SEQUENCE_START: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    /// The data is shorter than the header says.
+    Truncated,
+    /// The data does not start with the PSF2 magic.
+    BadMagic,
+    /// The header is too short, has no glyphs, or glyphs too small for their size.
+    BadHeader,
+    /// The unicode table contains invalid UTF-8.
+    BadUnicodeTable,
+}
+
+impl fmt::Display for PsfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PsfError::Truncated => "font data is truncated",
+            PsfError::BadMagic => "not a PSF2 font",
+            PsfError::BadHeader => "invalid font header",
+            PsfError::BadUnicodeTable => "invalid unicode table",
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PsfHeader {
+    magic: u32,
+    version: u32,
+    headersize: u32,
+    flags: u32,
+    glyphs: u32,
+    bytes_per_glyph: u32,
+    height: u32,
+    width: u32,
+}
+
+impl PsfHeader {
+    /// Reads the header at the start of `data`.
+    pub fn parse(data: &[u8]) -> Result<PsfHeader, PsfError> {
+        if data.len() < size_of::<PsfHeader>() {
+            return Err(PsfError::Truncated);
+        }
+        // SAFETY: `data` is long enough, and any bytes are a valid header.
+        let header = unsafe { ptr::read_unaligned(data.as_ptr().cast::<PsfHeader>()) };
+        if header.magic != PSF2_MAGIC {
+            return Err(PsfError::BadMagic);
+        }
+        let bytes_per_glyph = header.height as usize * (header.width as usize).div_ceil(8);
+        if (header.headersize as usize) < size_of::<PsfHeader>()
+            || header.glyphs == 0
+            || (header.bytes_per_glyph as usize) < bytes_per_glyph
+        {
+            return Err(PsfError::BadHeader);
+        }
+        if data.len() < header.unicode_table_offset() {
+            return Err(PsfError::Truncated);
+        }
+        Ok(header)
+    }
+
+    /// Where the unicode table of the font starts, right after the glyphs.
+    fn unicode_table_offset(&self) -> usize {
+        self.headersize as usize + self.glyphs as usize * self.bytes_per_glyph as usize
+    }
+
+    /// Maps the characters of the unicode table of `data` to their glyph.
+    /// Returns `None` if the font has no unicode table.
+    pub fn unicode_mapping(&self, data: &[u8]) -> Result<Option<HashMap<char, u32>>, PsfError> {
+        if self.flags & HAS_UNICODE_TABLE == 0 {
+            return Ok(None);
+        }
+
+        let table = data
+            .get(self.unicode_table_offset()..)
+            .ok_or(PsfError::Truncated)?;
+        let mut map = HashMap::new();
+        // the table may end without a separator.
+        let entries = table.strip_suffix(&[SEPARATOR]).unwrap_or(table);
+        for (glyph, entry) in entries.split(|&b| b == SEPARATOR).enumerate() {
+            let single = entry
+                .split(|&b| b == SEQUENCE_START)
+                .next()
+                .unwrap_or(entry);
+            let chars = str::from_utf8(single).map_err(|_| PsfError::BadUnicodeTable)?;
+            for ch in chars.chars() {
+                map.insert(ch, glyph as u32);
+            }
+        }
+        Ok(Some(map))
+    }
+}
+
+/// A parsed PSF2 font.
+pub struct Font<'a> {
+    header: PsfHeader,
+    data: &'a [u8],
+    mapping: Option<HashMap<char, u32>>,
+}
+
+impl fmt::Debug for Font<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Font")
+            .field("header", &self.header)
+            .field("unicode", &self.mapping.is_some())
+            .finish()
+    }
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Font<'a>, PsfError> {
+        let header = PsfHeader::parse(data)?;
+        let mapping = header.unicode_mapping(data)?;
+        Ok(Font {
+            header,
+            data,
+            mapping,
+        })
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.header.width as usize
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.header.height as usize
+    }
+
+    /// Number of bytes of each row of a glyph.
+    #[inline]
+    fn bytes_per_row(&self) -> usize {
+        self.width().div_ceil(8)
+    }
+
+    /// Returns the index of the glyph of `c`. Characters without a glyph get
+    /// the first one.
+    pub fn glyph_index(&self, c: char) -> u32 {
+        let index = match &self.mapping {
+            Some(mapping) => mapping.get(&c).copied().unwrap_or(0),
+            None => c as u32,
+        };
+        if index >= self.header.glyphs {
+            0
+        } else {
+            index
+        }
+    }
+
+    /// Returns the glyph of `c`.
+    pub fn glyph(&self, c: char) -> Glyph<'a> {
+        let size = self.header.bytes_per_glyph as usize;
+        let start = self.header.headersize as usize + self.glyph_index(c) as usize * size;
+        Glyph {
+            bitmap: &self.data[start..start + size],
+            bytes_per_row: self.bytes_per_row(),
+        }
+    }
+}
+
+/// The bitmap of a character.
+#[derive(Debug, Clone, Copy)]
+pub struct Glyph<'a> {
+    bitmap: &'a [u8],
+    bytes_per_row: usize,
+}
+
+impl<'a> Glyph<'a> {
+    #[inline]
+    pub fn bitmap(&self) -> &'a [u8] {
+        self.bitmap
+    }
+
+    /// Whether the pixel at column `x` and row `y` is set.
+    #[inline]
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.bitmap[y * self.bytes_per_row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
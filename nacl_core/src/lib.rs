@@ -0,0 +1,17 @@
+//! The parts of NaCl that do not touch hardware.
+//!
+//! What the kernel needs from the machine is behind small traits: a
+//! [framebuffer sink](console::FrameBufferSink) to draw characters on, the
+//! [number of the current processor](executor::CpuId) and a [timer
+//! source](executor::TimerSource). The kernel implements them on top of the
+//! hardware, and tests implement them on the host, so this crate can be tested
+//! with `cargo test`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod console;
+pub mod executor;
+pub mod font;
+pub mod sync;
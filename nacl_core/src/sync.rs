@@ -0,0 +1,126 @@
+//! An async mutex.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+
+/// A mutex whose [`lock`](Mutex::lock) waits by returning `Pending`, so tasks
+/// waiting for it do not keep their processor busy.
+///
+/// Only the last task that started waiting is woken when the mutex is unlocked.
+pub struct Mutex<T> {
+    inner: UnsafeCell<T>,
+    locked: AtomicBool,
+    waker: AtomicWaker,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+pub struct MutexLockFuture<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Future for MutexLockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+
+        // Fast path. Avoid registering this task's waker.
+        if let Some(guard) = mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+
+        mutex.waker.register(cx.waker());
+        // the mutex may have been unlocked before the waker was registered.
+        match mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(value),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    pub fn lock(&self) -> MutexLockFuture<'_, T> {
+        MutexLockFuture { mutex: self }
+    }
+
+    /// Spins until the lock is acquired.
+    pub fn lock_or_spin(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T> MutexGuard<'_, T> {
+    /// The mutex this guard locks.
+    #[inline]
+    pub fn mutex(this: &Self) -> &Mutex<T> {
+        this.mutex
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waker.wake();
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the guard holds the lock.
+        unsafe { &*self.mutex.inner.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: the guard holds the lock.
+        unsafe { &mut *self.mutex.inner.get() }
+    }
+}
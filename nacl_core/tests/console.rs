@@ -0,0 +1,103 @@
+use std::fmt::Write;
+
+use nacl_core::console::{Console, FrameBufferSink, FOREGROUND};
+
+/// Remembers what was drawn where.
+struct Screen {
+    columns: usize,
+    rows: usize,
+    cells: Vec<char>,
+    draws: usize,
+}
+
+impl Screen {
+    fn new(columns: usize, rows: usize) -> Screen {
+        Screen {
+            columns,
+            rows,
+            cells: vec!['\0'; columns * rows],
+            draws: 0,
+        }
+    }
+
+    fn line(&self, row: usize) -> String {
+        self.cells[row * self.columns..][..self.columns]
+            .iter()
+            .collect()
+    }
+}
+
+impl FrameBufferSink for Screen {
+    fn columns(&self) -> usize {
+        self.columns
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn draw_char(&mut self, c: char, column: usize, row: usize, fg: u32, _bg: u32) {
+        assert!(column < self.columns && row < self.rows);
+        assert_eq!(fg, FOREGROUND);
+        self.cells[row * self.columns + column] = c;
+        self.draws += 1;
+    }
+}
+
+fn lines<S: FrameBufferSink>(console: &Console<S>) -> Vec<String> {
+    (0..console.rows())
+        .map(|row| console.line(row).iter().collect())
+        .collect()
+}
+
+#[test]
+fn writes_on_last_line() {
+    let mut console = Console::new(Screen::new(4, 3));
+    console.write_str("ab").unwrap();
+    assert_eq!(lines(&console), ["    ", "    ", "ab  "]);
+    // only the new characters are drawn.
+    assert_eq!(console.sink().draws, 2);
+    assert_eq!(console.sink().line(2), "ab\0\0");
+}
+
+#[test]
+fn newline_scrolls() {
+    let mut console = Console::new(Screen::new(4, 3));
+    console.write_str("ab\ncd\n").unwrap();
+    assert_eq!(lines(&console), ["ab  ", "cd  ", "    "]);
+    // a new line redraws the whole grid.
+    for row in 0..3 {
+        assert_eq!(console.sink().line(row), lines(&console)[row]);
+    }
+
+    console.write_str("e\nf\ng").unwrap();
+    assert_eq!(lines(&console), ["e   ", "f   ", "g   "]);
+}
+
+#[test]
+fn long_lines_wrap() {
+    let mut console = Console::new(Screen::new(3, 2));
+    console.write_str("abcdefg").unwrap();
+    assert_eq!(lines(&console), ["def", "g  "]);
+    assert_eq!(console.sink().line(0), "def");
+    assert_eq!(console.sink().line(1), "g  ");
+
+    // a full line only wraps once the next character comes.
+    let mut console = Console::new(Screen::new(3, 2));
+    console.write_str("abc").unwrap();
+    assert_eq!(lines(&console), ["   ", "abc"]);
+    console.put('\n');
+    assert_eq!(lines(&console), ["abc", "   "]);
+}
+
+#[test]
+fn bare_newline() {
+    let mut console = Console::new(Screen::new(2, 2));
+    console.write_str("a").unwrap();
+    console.newline();
+    assert_eq!(lines(&console), ["a ", "  "]);
+    // not drawn until redrawn.
+    assert_eq!(console.sink().line(0), "\0\0");
+    console.redraw();
+    assert_eq!(console.sink().line(0), "a ");
+}
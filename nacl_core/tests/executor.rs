@@ -0,0 +1,217 @@
+use std::cell::Cell;
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use nacl_core::executor::{CpuId, Priority, RunQueues, Slot, TimerSource};
+use nacl_deque::DefaultEpoch;
+
+thread_local! {
+    static NOW: Cell<usize> = const { Cell::new(0) };
+    static CPU: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// A timer that only ticks when told to.
+struct Ticks;
+
+impl TimerSource for Ticks {
+    fn now() -> Wrapping<usize> {
+        Wrapping(NOW.get())
+    }
+}
+
+fn tick(ticks: usize) {
+    NOW.set(NOW.get().wrapping_add(ticks));
+}
+
+/// Every test thread is a processor, numbered by `CPU`.
+struct Thread;
+
+impl CpuId for Thread {
+    fn current() -> Option<u32> {
+        CPU.get()
+    }
+}
+
+type Queues = RunQueues<u32, DefaultEpoch, Ticks>;
+
+fn drain(queues: &Queues) -> Vec<u32> {
+    std::iter::from_fn(|| queues.pop()).collect()
+}
+
+#[test]
+fn most_important_first() {
+    let queues = Queues::new();
+    assert!(queues.is_empty());
+    queues.push(1, Priority::Background);
+    queues.push(2, Priority::Normal);
+    queues.push(3, Priority::BottomHalf);
+    queues.push(4, Priority::Normal);
+    queues.push(5, Priority::Interactive);
+    assert!(!queues.is_empty());
+    assert_eq!(drain(&queues), [3, 5, 2, 4, 1]);
+    assert!(queues.is_empty());
+}
+
+#[test]
+fn pinned_before_unpinned() {
+    let queues = Queues::new();
+    queues.push(1, Priority::Normal);
+    queues.push_pinned(2, Priority::Normal);
+    queues.push_pinned(3, Priority::Background);
+    assert_eq!(drain(&queues), [2, 1, 3]);
+}
+
+#[test]
+fn overdue_queue_goes_first() {
+    NOW.set(usize::MAX - 5);
+    let queues = Queues::new();
+    queues.push(1, Priority::Background);
+    tick(Priority::Background.max_wait());
+    queues.push(2, Priority::Normal);
+    queues.push(3, Priority::Normal);
+    // the background task waited exactly its maximum, which is not overdue.
+    assert_eq!(queues.pop(), Some(2));
+
+    tick(1);
+    queues.push(4, Priority::Normal);
+    // now it is, and waited longer past its maximum than the normal queue.
+    assert_eq!(queues.pop(), Some(1));
+    assert_eq!(drain(&queues), [3, 4]);
+}
+
+#[test]
+fn wait_starts_when_queued() {
+    let queues = Queues::new();
+    tick(1000);
+    // the background queue was never served, but only starts waiting now.
+    queues.push(1, Priority::Background);
+    queues.push(2, Priority::Normal);
+    assert_eq!(drain(&queues), [2, 1]);
+}
+
+#[test]
+fn steal_keeps_priority() {
+    let queues = Queues::new();
+    queues.push(1, Priority::Normal);
+    queues.push(2, Priority::Interactive);
+    queues.push_pinned(3, Priority::BottomHalf);
+
+    let thief = Queues::new();
+    assert!(queues.stealers().steal_into(&thief));
+    // the most important stealable queue is taken, pinned tasks are not.
+    assert_eq!(drain(&thief), [2]);
+    assert!(queues.stealers().steal_into(&thief));
+    assert_eq!(drain(&thief), [1]);
+    assert!(!queues.stealers().steal_into(&thief));
+    assert_eq!(drain(&queues), [3]);
+}
+
+#[test]
+fn slot_home() {
+    CPU.set(Some(3));
+    let slot = Arc::new(Slot::<(), Thread>::new(()));
+    assert_eq!(slot.home(), 3);
+    assert!(slot.is_home());
+
+    let other = slot.clone();
+    thread::spawn(move || {
+        CPU.set(Some(4));
+        assert!(!other.is_home());
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+#[should_panic = "unknown processor"]
+fn slot_needs_processor() {
+    Slot::<(), Thread>::new(());
+}
+
+#[test]
+fn slot_ownership() {
+    CPU.set(Some(0));
+    let slot = Slot::<u32, Thread>::new(7);
+    assert!(slot.try_acquire());
+    // SAFETY: acquired above.
+    assert_eq!(unsafe { slot.task() }, &mut Some(7));
+    assert!(!slot.release());
+
+    assert!(slot.try_acquire());
+    // popped elsewhere while owned: not acquired, but queued again on release.
+    assert!(!slot.try_acquire());
+    assert!(slot.release());
+    assert!(slot.try_acquire());
+    assert!(!slot.release());
+
+    assert!(slot.try_acquire());
+    // SAFETY: acquired above.
+    assert_eq!(unsafe { slot.take() }, Some(7));
+    assert_eq!(unsafe { slot.take() }, None);
+    assert!(!slot.release());
+}
+
+/// What the threads of `slot_concurrent` share.
+#[derive(Default)]
+struct Shared {
+    slot: Option<Slot<(), Thread>>,
+    /// set while a thread polls the task.
+    polling: AtomicBool,
+    /// the last wake.
+    woken: AtomicUsize,
+    /// the last wake a poll started after.
+    seen: AtomicUsize,
+}
+
+impl Shared {
+    /// Polls the task if it can be acquired, and again while that is asked for.
+    fn poll(&self) {
+        let slot = self.slot.as_ref().unwrap();
+        if !slot.try_acquire() {
+            return;
+        }
+        loop {
+            assert!(
+                !self.polling.swap(true, Ordering::SeqCst),
+                "polled twice at once"
+            );
+            let woken = self.woken.load(Ordering::SeqCst);
+            self.seen.fetch_max(woken, Ordering::SeqCst);
+            self.polling.store(false, Ordering::SeqCst);
+            // a task queued again would be popped by this thread.
+            if !slot.release() || !slot.try_acquire() {
+                return;
+            }
+        }
+    }
+}
+
+/// However polls and wakes interleave, a task is never polled by two threads
+/// at once, and the last wake leads to a poll that starts after it.
+#[test]
+fn slot_concurrent() {
+    const WAKES: usize = 10_000;
+
+    CPU.set(Some(0));
+    let shared = Arc::new(Shared {
+        slot: Some(Slot::new(())),
+        ..Shared::default()
+    });
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for wake in 1..=WAKES {
+                    shared.woken.fetch_max(wake, Ordering::SeqCst);
+                    shared.poll();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(shared.seen.load(Ordering::SeqCst), WAKES);
+}
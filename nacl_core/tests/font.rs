@@ -0,0 +1,113 @@
+use nacl_core::font::{Font, PsfError, PsfHeader};
+
+static TERMINUS: &[u8] = include_bytes!("../../nacl/src/font/ter-u20n.psf");
+
+/// Builds a font of `glyphs` 8x2 glyphs, where glyph `n` has both rows set to
+/// `n`, followed by `unicode` if it is not `None`.
+fn font(glyphs: u32, unicode: Option<&[u8]>) -> Vec<u8> {
+    let header = [
+        0x864A_B572,
+        0,
+        32,
+        unicode.is_some() as u32,
+        glyphs,
+        2,
+        2,
+        8,
+    ];
+    let mut data: Vec<u8> = header.iter().flat_map(|n: &u32| n.to_le_bytes()).collect();
+    for glyph in 0..glyphs {
+        data.extend([glyph as u8; 2]);
+    }
+    data.extend(unicode.unwrap_or_default());
+    data
+}
+
+#[test]
+fn terminus() {
+    let font = Font::parse(TERMINUS).unwrap();
+    assert_eq!((font.width(), font.height()), (10, 20));
+    // the glyph of 'A' is not at index 'A', but found through the unicode table.
+    assert_ne!(font.glyph_index('A'), 0);
+    assert_ne!(font.glyph_index('A'), font.glyph_index('B'));
+    assert_eq!(
+        font.glyph(' ').bitmap().iter().filter(|&&b| b != 0).count(),
+        0
+    );
+    // the middle of the horizontal bar of 'H'.
+    assert!((0..font.width()).any(|x| font.glyph('H').pixel(x, 10)));
+    assert!(!font.glyph(' ').pixel(5, 10));
+}
+
+#[test]
+fn unicode_mapping() {
+    // 'a' and 'α' share glyph 1, glyph 2 has a combining sequence after 'b'.
+    let table = b"\xFFa\xCE\xB1\xFFb\xFEb\xCC\x81\xFF";
+    let data = font(3, Some(table));
+    let header = PsfHeader::parse(&data).unwrap();
+    let mapping = header.unicode_mapping(&data).unwrap().unwrap();
+    assert_eq!(mapping.len(), 3);
+    assert_eq!(mapping[&'a'], 1);
+    assert_eq!(mapping[&'α'], 1);
+    assert_eq!(mapping[&'b'], 2);
+
+    let font = Font::parse(&data).unwrap();
+    assert_eq!(font.glyph('α').bitmap(), [1, 1]);
+    // unmapped characters get the first glyph.
+    assert_eq!(font.glyph_index('z'), 0);
+}
+
+#[test]
+fn unterminated_table() {
+    let data = font(2, Some(b"x\xFFy"));
+    let mapping = PsfHeader::parse(&data).unwrap().unicode_mapping(&data);
+    let mapping = mapping.unwrap().unwrap();
+    assert_eq!((mapping[&'x'], mapping[&'y']), (0, 1));
+}
+
+#[test]
+fn without_table() {
+    let data = font(3, None);
+    assert_eq!(
+        PsfHeader::parse(&data).unwrap().unicode_mapping(&data),
+        Ok(None)
+    );
+
+    let font = Font::parse(&data).unwrap();
+    // glyphs are indexed by code point, out of range ones get the first glyph.
+    assert_eq!(font.glyph('\u{2}').bitmap(), [2, 2]);
+    assert_eq!(font.glyph('a').bitmap(), [0, 0]);
+    assert!(font.glyph('\u{2}').pixel(6, 1));
+    assert!(!font.glyph('\u{2}').pixel(7, 1));
+}
+
+#[test]
+fn errors() {
+    let data = font(2, None);
+    assert_eq!(
+        PsfHeader::parse(&data[..16]).unwrap_err(),
+        PsfError::Truncated
+    );
+    assert_eq!(
+        PsfHeader::parse(&data[..35]).unwrap_err(),
+        PsfError::Truncated
+    );
+
+    let mut bad = data.clone();
+    bad[0] = 0;
+    assert_eq!(PsfHeader::parse(&bad).unwrap_err(), PsfError::BadMagic);
+
+    // headersize, glyphs and bytes_per_glyph.
+    for (offset, value) in [(8, 28), (16, 0), (20, 1)] {
+        let mut bad = data.clone();
+        bad[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        assert_eq!(PsfHeader::parse(&bad).unwrap_err(), PsfError::BadHeader);
+    }
+    // a width of 9 needs two bytes per row.
+    let mut bad = data.clone();
+    bad[28..32].copy_from_slice(&9u32.to_le_bytes());
+    assert_eq!(PsfHeader::parse(&bad).unwrap_err(), PsfError::BadHeader);
+
+    let data = font(1, Some(b"\xC3\xFF"));
+    assert_eq!(Font::parse(&data).unwrap_err(), PsfError::BadUnicodeTable);
+}
@@ -0,0 +1,102 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures_util::task::{waker, ArcWake};
+use nacl_core::sync::Mutex;
+
+/// Counts how often it was woken.
+#[derive(Default)]
+struct Wakes(AtomicUsize);
+
+impl ArcWake for Wakes {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Wakes {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn wakes() -> (Arc<Wakes>, Waker) {
+    let wakes = Arc::new(Wakes::default());
+    let waker = waker(wakes.clone());
+    (wakes, waker)
+}
+
+#[test]
+fn lock_when_unlocked() {
+    let mutex = Mutex::new(1);
+    let (wakes, waker) = wakes();
+    let mut lock = pin!(mutex.lock());
+    let Poll::Ready(mut guard) = lock.as_mut().poll(&mut Context::from_waker(&waker)) else {
+        panic!("unlocked mutex not acquired");
+    };
+    *guard += 1;
+    assert!(mutex.is_locked());
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(!mutex.is_locked());
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+    assert_eq!(wakes.count(), 0);
+}
+
+#[test]
+fn unlock_wakes_waiter() {
+    let mutex = Mutex::new(());
+    let guard = mutex.lock_or_spin();
+
+    let (wakes, waker) = wakes();
+    let mut cx = Context::from_waker(&waker);
+    let mut lock = pin!(mutex.lock());
+    assert!(lock.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(wakes.count(), 0);
+
+    drop(guard);
+    assert_eq!(wakes.count(), 1);
+    assert!(lock.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test]
+fn try_lock_guard_wakes_waiter() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+
+    let (wakes, waker) = wakes();
+    let mut lock = pin!(mutex.lock());
+    assert!(lock
+        .as_mut()
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending());
+    drop(guard);
+    assert_eq!(wakes.count(), 1);
+}
+
+#[test]
+fn contended() {
+    const THREADS: usize = 4;
+    const STEPS: usize = 10_000;
+
+    let mutex = Arc::new(Mutex::new(0));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                for _ in 0..STEPS {
+                    *mutex.lock_or_spin() += 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let mutex = Arc::into_inner(mutex).unwrap();
+    assert_eq!(mutex.into_inner(), THREADS * STEPS);
+}
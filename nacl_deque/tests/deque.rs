@@ -0,0 +1,178 @@
+#![cfg(not(crossbeam_loom))]
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nacl_deque::host::{Injector, Worker};
+use nacl_deque::Steal::{self, Empty, Success};
+
+const STEPS: usize = 50_000;
+const THREADS: usize = 4;
+
+#[test]
+fn fifo_smoke() {
+    let w = Worker::new_fifo();
+    let s = w.stealer();
+    assert_eq!(w.pop(), None);
+    assert_eq!(s.steal(), Empty);
+
+    w.push(1);
+    assert_eq!(w.pop(), Some(1));
+
+    w.push(2);
+    w.push(3);
+    assert_eq!(s.steal(), Success(2));
+    assert_eq!(w.pop(), Some(3));
+    assert_eq!(w.pop(), None);
+}
+
+#[test]
+fn lifo_smoke() {
+    let w = Worker::new_lifo();
+    let s = w.stealer();
+
+    w.push(1);
+    w.push(2);
+    w.push(3);
+    assert_eq!(w.pop(), Some(3));
+    assert_eq!(s.steal(), Success(1));
+    assert_eq!(w.pop(), Some(2));
+    assert_eq!(s.steal(), Empty);
+}
+
+#[test]
+fn len_and_grow() {
+    let w = Worker::new_fifo();
+    let s = w.stealer();
+    for i in 0..STEPS {
+        w.push(i);
+        assert_eq!(w.len(), i + 1);
+    }
+    for i in 0..STEPS {
+        assert_eq!(s.steal(), Success(i));
+        assert_eq!(s.len(), STEPS - i - 1);
+    }
+    assert!(w.is_empty());
+}
+
+#[test]
+fn steal_batch() {
+    let w = Worker::new_fifo();
+    for i in 0..100 {
+        w.push(i);
+    }
+    let dest = Worker::new_fifo();
+    assert_eq!(w.stealer().steal_batch(&dest), Success(()));
+    let stolen = dest.len();
+    assert!(stolen > 0 && stolen <= 50);
+    assert_eq!(dest.pop(), Some(0));
+    assert_eq!(w.stealer().steal_batch_and_pop(&dest), Success(stolen));
+}
+
+#[test]
+fn injector_smoke() {
+    let q = Injector::new();
+    assert_eq!(q.steal(), Empty);
+    for i in 0..STEPS {
+        q.push(i);
+    }
+    assert_eq!(q.len(), STEPS);
+
+    let w = Worker::new_lifo();
+    assert_eq!(q.steal_batch_and_pop(&w), Success(0));
+    let mut next = 1 + w.len();
+    while w.pop().is_some() {}
+    loop {
+        match q.steal() {
+            Success(i) => {
+                assert_eq!(i, next);
+                next += 1;
+            }
+            Empty => break,
+            Steal::Retry => {}
+        }
+    }
+    assert_eq!(next, STEPS);
+}
+
+/// Every pushed value is popped or stolen exactly once.
+#[test]
+fn stress() {
+    for w in [Worker::new_fifo(), Worker::new_lifo()] {
+        let done = Arc::new(AtomicBool::new(false));
+        let seen = Arc::new(Mutex::new(vec![false; STEPS]));
+        let stolen = Arc::new(AtomicUsize::new(0));
+
+        let thieves: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let s = w.stealer();
+                let (done, seen, stolen) = (done.clone(), seen.clone(), stolen.clone());
+                thread::spawn(move || {
+                    let dest = Worker::new_fifo();
+                    let record = |i: usize| {
+                        assert!(!std::mem::replace(&mut seen.lock().unwrap()[i], true));
+                        stolen.fetch_add(1, Ordering::Relaxed);
+                    };
+                    while !done.load(Ordering::Acquire) || !s.is_empty() {
+                        if let Success(i) = s.steal_batch_and_pop(&dest) {
+                            record(i);
+                        }
+                        while let Some(i) = dest.pop() {
+                            record(i);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut popped = 0;
+        for i in 0..STEPS {
+            w.push(i);
+            if i % 3 == 0 {
+                if let Some(i) = w.pop() {
+                    assert!(!std::mem::replace(&mut seen.lock().unwrap()[i], true));
+                    popped += 1;
+                }
+            }
+        }
+        done.store(true, Ordering::Release);
+        for thief in thieves {
+            thief.join().unwrap();
+        }
+        while let Some(i) = w.pop() {
+            assert!(!std::mem::replace(&mut seen.lock().unwrap()[i], true));
+            popped += 1;
+        }
+
+        assert_eq!(popped + stolen.load(Ordering::Relaxed), STEPS);
+        assert!(seen.lock().unwrap().iter().all(|&seen| seen));
+    }
+}
+
+/// Dropping the deques drops the values that are left exactly once.
+#[test]
+fn destructors() {
+    struct Elem(Arc<AtomicUsize>);
+
+    impl Drop for Elem {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let w = Worker::new_fifo();
+    let q = Injector::new();
+    for _ in 0..1000 {
+        w.push(Elem(dropped.clone()));
+        q.push(Elem(dropped.clone()));
+    }
+    drop(w.pop());
+    drop(q.steal());
+    assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+    drop(w);
+    drop(q);
+    assert_eq!(dropped.load(Ordering::Relaxed), 2000);
+}
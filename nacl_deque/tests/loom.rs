@@ -0,0 +1,94 @@
+//! Model checks of the deques, run with
+//! `RUSTFLAGS="--cfg crossbeam_loom" cargo test -p nacl_deque --test loom --release`.
+
+#![cfg(crossbeam_loom)]
+
+use loom::sync::Arc;
+use loom::thread;
+use nacl_deque::host::{Injector, Worker};
+use nacl_deque::Steal;
+
+/// Steals with `steal` until it reports empty, collecting what was stolen.
+fn drain<T>(mut steal: impl FnMut() -> Steal<T>) -> Vec<T> {
+    let mut stolen = Vec::new();
+    loop {
+        match steal() {
+            Steal::Success(value) => stolen.push(value),
+            Steal::Empty => return stolen,
+            Steal::Retry => thread::yield_now(),
+        }
+    }
+}
+
+/// Checks that `values` holds every value in `0..count` exactly once.
+fn assert_all(mut values: Vec<u32>, count: u32) {
+    values.sort_unstable();
+    assert_eq!(values, (0..count).collect::<Vec<_>>());
+}
+
+fn pop_races_steal(w: fn() -> Worker<u32>) {
+    loom::model(move || {
+        let w = w();
+        w.push(0);
+        w.push(1);
+        let s = w.stealer();
+        let thief = thread::spawn(move || drain(|| s.steal()));
+
+        let mut values = Vec::new();
+        values.extend(w.pop());
+        values.extend(w.pop());
+        values.extend(thief.join().unwrap());
+        assert_all(values, 2);
+    });
+}
+
+#[test]
+fn fifo_pop_races_steal() {
+    pop_races_steal(Worker::new_fifo);
+}
+
+#[test]
+fn lifo_pop_races_steal() {
+    pop_races_steal(Worker::new_lifo);
+}
+
+#[test]
+fn steal_batch_races_pop() {
+    loom::model(|| {
+        let w = Worker::new_fifo();
+        for i in 0..3 {
+            w.push(i);
+        }
+        let s = w.stealer();
+        let thief = thread::spawn(move || {
+            let dest = Worker::new_fifo();
+            let mut stolen = drain(|| s.steal_batch_and_pop(&dest));
+            stolen.extend(std::iter::from_fn(|| dest.pop()));
+            stolen
+        });
+
+        let mut values: Vec<_> = std::iter::from_fn(|| w.pop()).collect();
+        values.extend(thief.join().unwrap());
+        values.extend(std::iter::from_fn(|| w.pop()));
+        assert_all(values, 3);
+    });
+}
+
+#[test]
+fn injector_push_races_steal() {
+    loom::model(|| {
+        let q = Arc::new(Injector::new());
+        let pusher = {
+            let q = q.clone();
+            thread::spawn(move || {
+                q.push(0);
+                q.push(1);
+            })
+        };
+
+        let mut values = drain(|| q.steal());
+        pusher.join().unwrap();
+        values.extend(drain(|| q.steal()));
+        assert_all(values, 2);
+    });
+}
@@ -0,0 +1,95 @@
+//! Procedural macros of the NaCl kernel.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{meta, parse_macro_input, Error, Ident, ItemFn, LitStr};
+
+/// What a test is expected to do.
+enum Expect {
+    Pass,
+    /// panic, with a message that contains the string if there is one.
+    Panic(Option<LitStr>),
+    /// cause a CPU exception, the one named by the `ExceptionVector` variant if
+    /// there is one.
+    Fault(Option<Ident>),
+}
+
+/// Marks a function as a test run inside the kernel by `cargo test`.
+///
+/// The function takes no arguments and returns `()`, and may be `async`.
+/// `#[kernel_test(should_panic)]` and `#[kernel_test(should_panic = "message")]`
+/// expect the test to panic, `#[kernel_test(should_fault)]` expects it to cause
+/// a CPU exception, and `#[kernel_test(should_fault = Page)]` a specific one,
+/// named like in `ExceptionVector`. Outside of tests, the function is removed.
+#[proc_macro_attribute]
+pub fn kernel_test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut expect = Expect::Pass;
+    let parser = meta::parser(|meta| {
+        if !matches!(expect, Expect::Pass) {
+            return Err(meta.error("only one expectation is allowed"));
+        }
+        if meta.path.is_ident("should_panic") {
+            expect = Expect::Panic(
+                if meta.input.is_empty() || meta.input.peek(syn::Token![,]) {
+                    None
+                } else {
+                    Some(meta.value()?.parse()?)
+                },
+            );
+            Ok(())
+        } else if meta.path.is_ident("should_fault") {
+            expect = Expect::Fault(
+                if meta.input.is_empty() || meta.input.peek(syn::Token![,]) {
+                    None
+                } else {
+                    Some(meta.value()?.parse()?)
+                },
+            );
+            Ok(())
+        } else {
+            Err(meta.error("expected `should_panic` or `should_fault`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+
+    let function = parse_macro_input!(item as ItemFn);
+    let signature = &function.sig;
+    if !signature.inputs.is_empty() || !matches!(signature.output, syn::ReturnType::Default) {
+        return Error::new_spanned(signature, "kernel tests take no arguments and return `()`")
+            .to_compile_error()
+            .into();
+    }
+
+    let name = &signature.ident;
+    let test = format_ident!("__KERNEL_TEST_{}", name, span = Span::call_site());
+    let run = if signature.asyncness.is_some() {
+        quote!(|| crate::task::block_on(#name()))
+    } else {
+        quote!(#name)
+    };
+    let expect = match expect {
+        Expect::Pass => quote!(crate::testing::Expect::Pass),
+        Expect::Panic(None) => quote!(crate::testing::Expect::Panic(None)),
+        Expect::Panic(Some(message)) => quote!(crate::testing::Expect::Panic(Some(#message))),
+        Expect::Fault(None) => quote!(crate::testing::Expect::Fault(None)),
+        Expect::Fault(Some(vector)) => quote!(crate::testing::Expect::Fault(Some(
+            crate::testing::ExceptionVector::#vector
+        ))),
+    };
+
+    quote! {
+        #[cfg(test)]
+        #function
+
+        #[cfg(test)]
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static #test: crate::testing::KernelTest = crate::testing::KernelTest {
+            name: concat!(module_path!(), "::", stringify!(#name)),
+            run: #run,
+            expect: #expect,
+        };
+    }
+    .into()
+}